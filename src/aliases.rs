@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::input::bitevents::BitEvent;
+use crate::input::InputError;
+
+/// Names for inputs and outputs, so that everything else can refer to a switch by what it does
+/// rather than where it is wired. Declared in a file with one per line:
+///
+///   # Comments and blank lines are ignored
+///   abort_handle = main_c:7
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Aliases {
+    names: BTreeMap<String, (String, u8)>,
+}
+
+impl Aliases {
+    pub fn load(filename: &str) -> Result<Aliases, InputError> {
+        Aliases::parse(&fs::read_to_string(filename)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Aliases, InputError> {
+        let mut names = BTreeMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || {
+                InputError::new(format!(
+                    "Invalid alias on line {}, expected <name> = <device>:<bit>: {}",
+                    number + 1,
+                    line
+                ))
+            };
+
+            let (name, input) = line.split_once('=').ok_or_else(invalid)?;
+            let (dev_name, bit) = input.trim().rsplit_once(':').ok_or_else(invalid)?;
+            let name = name.trim();
+
+            if name.is_empty() || name.contains(':') {
+                return Err(invalid());
+            }
+
+            let input = (String::from(dev_name), bit.parse().map_err(|_| invalid())?);
+            if names.insert(String::from(name), input).is_some() {
+                return Err(InputError::new(format!(
+                    "Alias {} is declared more than once",
+                    name
+                )));
+            }
+        }
+
+        Ok(Aliases { names })
+    }
+
+    /// The input an alias stands for
+    pub fn get(&self, name: &str) -> Option<&(String, u8)> {
+        self.names.get(name)
+    }
+
+    /// Find an input by alias, or as <device>:<bit>
+    pub fn resolve(&self, input: &str) -> Result<(String, u8), InputError> {
+        if let Some(input) = self.get(input) {
+            return Ok(input.clone());
+        }
+
+        match input.rsplit_once(':') {
+            Some((dev_name, bit)) => Ok((String::from(dev_name), bit.parse()?)),
+            None => Err(InputError::new(format!("Unknown input '{}'", input))),
+        }
+    }
+
+    /// The alias for an input, if it has one
+    pub fn name_of(&self, dev_name: &str, bit: u8) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(_, input)| input.0 == dev_name && input.1 == bit)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// An event as it should show up in the logs, by alias if it has one
+    pub fn describe(&self, event: &BitEvent) -> String {
+        match self.name_of(&event.dev_name, event.bit) {
+            Some(name) => format!("{} {}", name, event),
+            None => event.to_string(),
+        }
+    }
+
+    /// Outputs can be sent with an alias as the device name, the bit is taken from the alias
+    pub fn resolve_outputs(&self, events: Vec<BitEvent>) -> Vec<BitEvent> {
+        events
+            .into_iter()
+            .map(|event| match self.get(&event.dev_name) {
+                Some((dev_name, bit)) => BitEvent {
+                    dev_name: dev_name.clone(),
+                    bit: *bit,
+                    value: event.value,
+                },
+                None => event,
+            })
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &(String, u8))> {
+        self.names.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Aliases;
+    use crate::input::bitevents::BitEvent;
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let aliases = Aliases::parse(
+            "
+# Handles
+abort_handle = main_c:7
+launch_light=main_a:12  # Green
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            aliases.get("abort_handle"),
+            Some(&(String::from("main_c"), 7))
+        );
+        assert_eq!(aliases.name_of("main_a", 12), Some("launch_light"));
+        assert_eq!(aliases.resolve("main_b:3"), Ok((String::from("main_b"), 3)));
+        assert!(aliases.resolve("nothing").is_err());
+
+        assert!(Aliases::parse("abort_handle main_c:7").is_err());
+        assert!(Aliases::parse("abort_handle = main_c").is_err());
+        assert!(Aliases::parse("a = main_c:7\na = main_c:8").is_err());
+    }
+
+    #[test]
+    fn test_outputs() {
+        let aliases = Aliases::parse("launch_light = main_a:12").unwrap();
+
+        assert_eq!(
+            aliases.resolve_outputs(vec![event("launch_light", 0, 1), event("main_b", 3, 1)]),
+            vec![event("main_a", 12, 1), event("main_b", 3, 1)]
+        );
+        assert_eq!(
+            aliases.describe(&event("main_a", 12, 1)),
+            "launch_light { dev: main_a, bit 12: 1 }"
+        );
+    }
+}
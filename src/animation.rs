@@ -0,0 +1,375 @@
+use std::time::{Duration, Instant};
+
+use crate::aliases::Aliases;
+use crate::bindfiles::parse_duration;
+use crate::input::bitevents::BitEvent;
+use crate::input::InputError;
+
+// Heartbeat timing: two short beats, then a rest for the remainder of the period
+const BEAT_ON: Duration = Duration::from_millis(100);
+const BEAT_OFF: Duration = Duration::from_millis(150);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// On and off for `interval` each, `count` times or until stopped, ending on
+    Blink {
+        interval: Duration,
+        count: Option<usize>,
+    },
+    /// On once for `width`, then off
+    Pulse { width: Duration },
+    /// Light each pin of the group in turn, for `interval` each
+    Chase { interval: Duration },
+    /// A double beat every `period`
+    Heartbeat { period: Duration },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub dev_name: String,
+    pub pins: Vec<u8>,
+    pub pattern: Pattern,
+}
+
+impl Animation {
+    /// Parse an animation such as "chase 100ms main_b:0 main_b:1 main_b:2", given as the pattern,
+    /// its timing and the outputs it drives, by alias or as <device>:<bit>. The outputs all have
+    /// to be on the same device. Blinks carry on until stopped
+    pub fn parse(spec: &str, aliases: &Aliases) -> Result<Animation, InputError> {
+        let words: Vec<&str> = spec.split_whitespace().collect();
+
+        if words.len() < 3 {
+            return Err(InputError::new(format!(
+                "Invalid animation '{}', expected <pattern> <time> <output>...",
+                spec
+            )));
+        }
+
+        let time = parse_duration(words[1])?;
+        let pattern = match words[0] {
+            "blink" => Pattern::Blink {
+                interval: time,
+                count: None,
+            },
+            "pulse" => Pattern::Pulse { width: time },
+            "chase" => Pattern::Chase { interval: time },
+            "heartbeat" => Pattern::Heartbeat { period: time },
+            unknown => {
+                return Err(InputError::new(format!(
+                    "Unknown animation pattern '{}'",
+                    unknown
+                )))
+            }
+        };
+
+        let outputs = words[2..]
+            .iter()
+            .map(|output| aliases.resolve(output))
+            .collect::<Result<Vec<_>, _>>()?;
+        let dev_name = outputs[0].0.clone();
+
+        if outputs.iter().any(|(other, _)| *other != dev_name) {
+            return Err(InputError::new(format!(
+                "Animation '{}' spans more than one device",
+                spec
+            )));
+        }
+
+        Ok(Animation {
+            dev_name,
+            pins: outputs.into_iter().map(|(_, pin)| pin).collect(),
+            pattern,
+        })
+    }
+
+    /// The level of every pin for the given step, and how long to hold it before the next one.
+    /// The last step has nothing to hold for
+    fn frame(&self, step: usize) -> (Vec<u8>, Option<Duration>) {
+        let all = |value: u8| vec![value; self.pins.len()];
+
+        match self.pattern {
+            Pattern::Blink { interval, count } => match count {
+                // Finally, leave it on once done blinking
+                Some(count) if step >= count * 2 => (all(1), None),
+                _ => (all(((step + 1) % 2) as u8), Some(interval)),
+            },
+            Pattern::Pulse { width } => match step {
+                0 => (all(1), Some(width)),
+                _ => (all(0), None),
+            },
+            Pattern::Chase { interval } => {
+                let lit = step % self.pins.len().max(1);
+                let values = (0..self.pins.len()).map(|i| (i == lit) as u8).collect();
+                (values, Some(interval))
+            }
+            Pattern::Heartbeat { period } => {
+                let rest = period.saturating_sub(BEAT_ON * 2 + BEAT_OFF);
+                match step % 4 {
+                    0 | 2 => (all(1), Some(BEAT_ON)),
+                    1 => (all(0), Some(BEAT_OFF)),
+                    _ => (all(0), Some(rest)),
+                }
+            }
+        }
+    }
+
+    fn uses(&self, dev_name: &str, pin: u8) -> bool {
+        self.dev_name == dev_name && self.pins.contains(&pin)
+    }
+}
+
+struct Running {
+    animation: Animation,
+    step: usize,
+    next: Instant,
+}
+
+/// Runs output animations from the main loop. Handlers start and stop them, and every pin is
+/// driven by at most one animation at a time
+pub struct Scheduler {
+    running: Vec<Running>,
+    // Outputs to send on the next tick, from animations that were stopped
+    pending: Vec<BitEvent>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            running: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Start an animation on the next tick, replacing whatever was running on any of its pins
+    pub fn start(&mut self, animation: Animation, now: Instant) {
+        for &pin in &animation.pins {
+            self.remove(&animation.dev_name, pin);
+        }
+
+        // The new animation takes over its pins, so don't turn them off again
+        self.pending
+            .retain(|event| !animation.uses(&event.dev_name, event.bit));
+
+        debug!("Starting {:?}", animation);
+        self.running.push(Running {
+            animation,
+            step: 0,
+            next: now,
+        });
+    }
+
+    /// Stop whatever is animating the given pin, turning off all of its pins
+    pub fn stop(&mut self, dev_name: &str, pin: u8) {
+        self.remove(dev_name, pin);
+    }
+
+    fn remove(&mut self, dev_name: &str, pin: u8) {
+        if let Some(index) = self
+            .running
+            .iter()
+            .position(|running| running.animation.uses(dev_name, pin))
+        {
+            let stopped = self.running.remove(index).animation;
+            debug!("Stopping {:?}", stopped);
+
+            for &pin in &stopped.pins {
+                self.pending.push(BitEvent {
+                    dev_name: stopped.dev_name.clone(),
+                    bit: pin,
+                    value: 0,
+                });
+            }
+        }
+    }
+
+    /// Step every animation that is due, returning the outputs to set
+    pub fn tick(&mut self, now: Instant) -> Vec<BitEvent> {
+        let mut events: Vec<BitEvent> = self.pending.drain(..).collect();
+
+        self.running.retain_mut(|running| {
+            while running.next <= now {
+                let (values, hold) = running.animation.frame(running.step);
+                let animation = &running.animation;
+
+                // Only the latest level of each pin matters when catching up
+                events.retain(|event| !animation.uses(&event.dev_name, event.bit));
+                events.extend(
+                    animation
+                        .pins
+                        .iter()
+                        .zip(values)
+                        .map(|(&pin, value)| BitEvent {
+                            dev_name: animation.dev_name.clone(),
+                            bit: pin,
+                            value,
+                        }),
+                );
+
+                match hold {
+                    // Step from the schedule rather than from now, so timing doesn't drift
+                    Some(hold) => running.next += hold.max(Duration::from_millis(1)),
+                    None => return false,
+                }
+                running.step += 1;
+            }
+            true
+        });
+
+        events
+    }
+
+    /// When the next animation step is due, if anything is running
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let pending = if self.pending.is_empty() {
+            None
+        } else {
+            Some(Instant::now())
+        };
+
+        self.running
+            .iter()
+            .map(|running| running.next)
+            .chain(pending)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Animation, Pattern, Scheduler};
+    use crate::aliases::Aliases;
+    use crate::input::bitevents::BitEvent;
+    use std::time::{Duration, Instant};
+
+    fn event(bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from("leds"),
+            bit,
+            value,
+        }
+    }
+
+    fn animation(pins: Vec<u8>, pattern: Pattern) -> Animation {
+        Animation {
+            dev_name: String::from("leds"),
+            pins,
+            pattern,
+        }
+    }
+
+    #[test]
+    fn test_blink() {
+        let mut scheduler = Scheduler::new();
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+
+        scheduler.start(
+            animation(
+                vec![1],
+                Pattern::Blink {
+                    interval,
+                    count: Some(2),
+                },
+            ),
+            start,
+        );
+
+        assert_eq!(scheduler.tick(start), vec![event(1, 1)]);
+        assert_eq!(scheduler.next_deadline(), Some(start + interval));
+        assert_eq!(scheduler.tick(start + interval / 2), vec![]);
+        assert_eq!(scheduler.tick(start + interval), vec![event(1, 0)]);
+        assert_eq!(scheduler.tick(start + interval * 2), vec![event(1, 1)]);
+        // Ends on, then it's done
+        assert_eq!(scheduler.tick(start + interval * 4), vec![event(1, 1)]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_chase() {
+        let mut scheduler = Scheduler::new();
+        let start = Instant::now();
+        let interval = Duration::from_millis(50);
+
+        scheduler.start(animation(vec![4, 5, 6], Pattern::Chase { interval }), start);
+
+        assert_eq!(
+            scheduler.tick(start),
+            vec![event(4, 1), event(5, 0), event(6, 0)]
+        );
+        assert_eq!(
+            scheduler.tick(start + interval),
+            vec![event(4, 0), event(5, 1), event(6, 0)]
+        );
+        assert_eq!(
+            scheduler.tick(start + interval * 3),
+            vec![event(4, 1), event(5, 0), event(6, 0)]
+        );
+    }
+
+    #[test]
+    fn test_replace_and_stop() {
+        let mut scheduler = Scheduler::new();
+        let start = Instant::now();
+        let interval = Duration::from_millis(50);
+
+        scheduler.start(animation(vec![1, 2], Pattern::Chase { interval }), start);
+        scheduler.tick(start);
+
+        // Taking over pin 2 stops the chase, pin 1 is turned off
+        scheduler.start(
+            animation(
+                vec![2],
+                Pattern::Pulse {
+                    width: Duration::from_millis(200),
+                },
+            ),
+            start,
+        );
+        assert_eq!(scheduler.tick(start), vec![event(1, 0), event(2, 1)]);
+
+        scheduler.stop("leds", 2);
+        assert_eq!(scheduler.tick(start + interval), vec![event(2, 0)]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let aliases = Aliases::parse("status = leds:7").unwrap();
+
+        assert_eq!(
+            Animation::parse("chase 100ms leds:4 leds:5", &aliases),
+            Ok(animation(
+                vec![4, 5],
+                Pattern::Chase {
+                    interval: Duration::from_millis(100)
+                }
+            ))
+        );
+        assert_eq!(
+            Animation::parse(" heartbeat 1s status ", &aliases),
+            Ok(animation(
+                vec![7],
+                Pattern::Heartbeat {
+                    period: Duration::from_secs(1)
+                }
+            ))
+        );
+        assert!(Animation::parse("pulse 1s", &aliases).is_err());
+        assert!(Animation::parse("strobe 1s leds:1", &aliases).is_err());
+        assert!(Animation::parse("blink 1s leds:1 main_a:1", &aliases).is_err());
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let animation = animation(
+            vec![0],
+            Pattern::Heartbeat {
+                period: Duration::from_secs(1),
+            },
+        );
+
+        let total: Duration = (0..4).map(|step| animation.frame(step).1.unwrap()).sum();
+        assert_eq!(total, Duration::from_secs(1));
+        assert_eq!(animation.frame(4), animation.frame(0));
+    }
+}
@@ -6,7 +6,14 @@ use i2cdev::linux::LinuxI2CDevice;
 use std::env;
 use std::process;
 
-const REGISTER_COUNT: u8 = 0x15;
+#[allow(dead_code)]
+#[path = "../input/mcp23017/registers.rs"]
+mod registers;
+
+use registers::{Iocon, Port, Register};
+
+// Every register of both ports
+const REGISTER_COUNT: u8 = 0x16;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -22,11 +29,19 @@ fn main() {
     let mut dev = LinuxI2CDevice::new(&args[1], address).expect("Could not open device");
 
     match dev.smbus_read_i2c_block_data(0x00, REGISTER_COUNT) {
+        Ok(ref data) if data.len() < REGISTER_COUNT as usize => {
+            eprintln!("Short read, only got {} bytes", data.len())
+        }
         Ok(data) => {
+            // Assumes BANK 0, which is where the chip starts out. Reading IOCON will tell if not
             println!("Read values:");
-            (0..REGISTER_COUNT).zip(data).for_each(|(index,value)| {
-                println!("  {:02x}: {:02x}", index, value);
-            });
+            for register in Register::ALL.iter() {
+                let a = data[register.address(Port::A, false) as usize];
+                let b = data[register.address(Port::B, false) as usize];
+                println!("  {:8} A: {:02x} B: {:02x}", register.name(), a, b);
+            }
+
+            println!("{:?}", Iocon::from_bits(data[Register::IOCON.address(Port::A, false) as usize]));
         }
         Err(e) => eprintln!("Error reading: {}", e)
     }
@@ -6,9 +6,11 @@ use i2cdev::linux::LinuxI2CDevice;
 use std::env;
 use std::process;
 
-const IODIR: u8 = 0x00;
-const IPOL: u8 = 0x02;
-const GPPU: u8 = 0x0c;
+#[allow(dead_code)]
+#[path = "../input/mcp23017/registers.rs"]
+mod registers;
+
+use registers::{Port, Register};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -25,8 +27,10 @@ fn main() {
 
     println!("Setting registers...");
 
-    dev.smbus_write_word_data(IODIR, 0xFFFF).expect("Failed to set IODIR");
-    dev.smbus_write_word_data(IPOL, 0xFFFF).expect("Failed to set IPOL");
-    dev.smbus_write_word_data(GPPU, 0xFFFF).expect("Failed to set GPPU");
+    // Assumes the device is still in BANK 0, as it is from power on
+    for register in &[Register::IODIR, Register::IPOL, Register::GPPU] {
+        dev.smbus_write_word_data(register.address(Port::A, false), 0xFFFF)
+            .unwrap_or_else(|_| panic!("Failed to set {}", register.name()));
+    }
 }
         
@@ -1,16 +1,39 @@
+use crate::animation::Animation;
 use crate::input::InputError;
 use crate::simulation::{EventHandler, HandlerFunc};
 use crate::to_static;
 
+use std::time::{Duration, Instant};
+
 type SoundFile = Option<(&'static String, f64)>;
 
+/// The animation, if any, runs for as long as the input is on
 pub fn create_handler(
     handler_name: &'static str,
     on_file: SoundFile,
     off_file: SoundFile,
+    animation: Option<Animation>,
 ) -> Option<EventHandler> {
-    if on_file.is_some() || off_file.is_some() {
-        let handler_func: HandlerFunc = Box::new(move |value, _| {
+    if on_file.is_some() || off_file.is_some() || animation.is_some() {
+        let handler_func: HandlerFunc = Box::new(move |value, context, _| {
+            // Animations follow where the input is, even at startup
+            if let Some(ref animation) = animation {
+                let mut animations = context.animations.borrow_mut();
+                if value == 0 {
+                    animations.stop(&animation.dev_name, animation.pins[0]);
+                } else {
+                    animations.start(animation.clone(), Instant::now());
+                }
+            }
+
+            if context.initial {
+                debug!(
+                    "Not playing sounds for the initial state of {}",
+                    handler_name
+                );
+                return;
+            }
+
             if value == 0 {
                 if let Some((off_filename, volume)) = off_file {
                     info!("Playing off sound for {}", handler_name);
@@ -38,7 +61,8 @@ pub fn split_sound_line(line: &str) -> Result<Vec<&str>, InputError> {
 
     debug!("Got definition for input {:?}", parts);
 
-    if parts.len() != 5 {
+    // The condition, alternate sound and animation are optional
+    if !(5..=8).contains(&parts.len()) {
         return Err(InputError::new(format!(
             "Incorrect number of elements for {}",
             line
@@ -76,9 +100,28 @@ pub fn parse_sound_filename(filename: &str) -> Result<SoundFile, InputError> {
     }
 }
 
+// Parse a duration such as "500ms" or "1.5s"
+pub fn parse_duration(duration: &str) -> Result<Duration, InputError> {
+    use std::str::FromStr;
+
+    let duration = duration.trim();
+    if let Some(millis) = duration.strip_suffix("ms") {
+        Ok(Duration::from_millis(u64::from_str(millis)?))
+    } else if let Some(seconds) = duration.strip_suffix('s') {
+        Duration::try_from_secs_f64(f64::from_str(seconds)?)
+            .map_err(|e| InputError::new(format!("Invalid duration '{}': {}", duration, e)))
+    } else {
+        Err(InputError::new(format!(
+            "Invalid duration '{}', expected ms or s",
+            duration
+        )))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_sound_filename;
+    use super::{parse_duration, parse_sound_filename};
+    use std::time::Duration;
 
     #[test]
     fn test_empty_filename() {
@@ -107,4 +150,12 @@ mod tests {
     fn test_filename_bad_volume() {
         assert!(parse_sound_filename("testing:goes to 11").is_err());
     }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("-1s").is_err());
+    }
 }
@@ -0,0 +1,286 @@
+use i2cdev::core::I2CDevice;
+use serde::Deserialize;
+
+use std::path::PathBuf;
+
+use crate::input::bitevents::BitEvent;
+use crate::input::InputError;
+
+// Commands
+const OSCILLATOR_ON: u8 = 0x21;
+const DISPLAY_ON: u8 = 0x81;
+const DIMMING: u8 = 0xE0;
+
+// Display RAM holds 8 rows of 16 bits, rows are 2 bytes apart
+const RAM_ROWS: usize = 8;
+
+// The position that controls the colon, on for any non-zero value
+pub const COLON: u8 = 0x80;
+// Set on a character to light the decimal point after it
+pub const DECIMAL_POINT: u8 = 0x80;
+
+// The colon segment on its row
+const COLON_SEGMENTS: u8 = 0x02;
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayModel {
+    Ht16k33,
+}
+
+/// An HT16K33 driving 7-segment digits. Each "<dev_name>:<position>" output event sets the
+/// character shown at that position, see segments for the encoding
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct DisplayConfig {
+    pub device_type: DisplayModel,
+    pub dev_path: PathBuf,
+    pub dev_name: String,
+    pub address: u16,
+    // The row (common cathode) driving each digit, left to right. The default matches the
+    // Adafruit 4 digit backpacks, which put the colon on row 2
+    #[serde(default = "default_digit_rows")]
+    pub digit_rows: Vec<u8>,
+    #[serde(default = "default_colon_row")]
+    pub colon_row: Option<u8>,
+    // 0-15
+    #[serde(default = "default_brightness")]
+    pub brightness: u8,
+}
+
+fn default_digit_rows() -> Vec<u8> {
+    vec![0, 1, 3, 4]
+}
+
+fn default_colon_row() -> Option<u8> {
+    Some(2)
+}
+
+fn default_brightness() -> u8 {
+    15
+}
+
+/// The segments (a-g as bits 0-6) for a character, if it can be shown at all
+fn segments(character: u8) -> Option<u8> {
+    let segments = match character & !DECIMAL_POINT {
+        b'0' | b'O' => 0x3F,
+        b'1' => 0x06,
+        b'2' => 0x5B,
+        b'3' => 0x4F,
+        b'4' => 0x66,
+        b'5' | b'S' => 0x6D,
+        b'6' => 0x7D,
+        b'7' => 0x07,
+        b'8' => 0x7F,
+        b'9' => 0x6F,
+        b'A' => 0x77,
+        b'b' => 0x7C,
+        b'C' => 0x39,
+        b'd' => 0x5E,
+        b'E' => 0x79,
+        b'F' => 0x71,
+        b'H' => 0x76,
+        b'L' => 0x38,
+        b'n' => 0x54,
+        b'o' => 0x5C,
+        b'P' => 0x73,
+        b'r' => 0x50,
+        b't' => 0x78,
+        b'U' => 0x3E,
+        b'-' => 0x40,
+        b'_' => 0x08,
+        b' ' => 0x00,
+        _ => return None,
+    };
+
+    Some(segments | (character & DECIMAL_POINT))
+}
+
+/// An HT16K33 showing characters on 7-segment digits
+pub struct HT16K33<D: I2CDevice> {
+    pub dev_name: String,
+    dev: D,
+    digit_rows: Vec<u8>,
+    colon_row: Option<u8>,
+    brightness: u8,
+    rows: [u8; RAM_ROWS],
+    // Set up again before the next write, after a failure that may have been a reset
+    needs_setup: bool,
+}
+
+impl<D> HT16K33<D>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    /// Make sure a batch of output events only sets positions and characters that exist
+    pub fn check_outputs(&self, bits: &[BitEvent]) -> Result<(), InputError> {
+        for event in bits {
+            if event.bit == COLON {
+                if self.colon_row.is_none() {
+                    return Err(InputError::new(format!("{} has no colon", self.dev_name)));
+                }
+            } else if usize::from(event.bit) >= self.digit_rows.len() {
+                return Err(InputError::new(format!(
+                    "Invalid position {} for {} with {} digits",
+                    event.bit,
+                    self.dev_name,
+                    self.digit_rows.len()
+                )));
+            } else if segments(event.value).is_none() {
+                return Err(InputError::new(format!(
+                    "{} can't show {:?}",
+                    self.dev_name,
+                    char::from(event.value & !DECIMAL_POINT)
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Update the display from a batch of output events, in a single write
+    pub fn set_outputs(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        self.check_outputs(bits)?;
+
+        for event in bits {
+            match (event.bit, self.colon_row) {
+                (COLON, Some(row)) => {
+                    self.rows[usize::from(row)] = if event.value != 0 { COLON_SEGMENTS } else { 0 };
+                }
+                (position, _) => {
+                    let row = self.digit_rows[usize::from(position)];
+                    self.rows[usize::from(row)] = segments(event.value).unwrap_or(0);
+                }
+            }
+        }
+
+        if self.needs_setup {
+            self.setup()?;
+        }
+
+        let result = self.write_rows();
+        if result.is_err() {
+            self.needs_setup = true;
+        }
+        Ok(result?)
+    }
+
+    fn setup(&mut self) -> Result<(), D::Error> {
+        self.dev.smbus_write_byte(OSCILLATOR_ON)?;
+        self.dev.smbus_write_byte(DIMMING | self.brightness)?;
+        self.dev.smbus_write_byte(DISPLAY_ON)?;
+        self.needs_setup = false;
+        Ok(())
+    }
+
+    /// Write the whole display RAM, starting from address 0
+    fn write_rows(&mut self) -> Result<(), D::Error> {
+        let mut data = vec![0x00];
+        for &row in &self.rows {
+            data.push(row);
+            data.push(0x00);
+        }
+        self.dev.write(&data)
+    }
+}
+
+/// Validate the config for an already opened display, then turn it on blank
+pub fn setup_ht16k33<D>(dev: D, config: &DisplayConfig) -> Result<HT16K33<D>, InputError>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    let rows = config.digit_rows.iter().chain(config.colon_row.iter());
+    if let Some(row) = rows.clone().find(|&&row| usize::from(row) >= RAM_ROWS) {
+        return Err(InputError::new(format!(
+            "Invalid row {} for {}, expected 0-{}",
+            row,
+            config.dev_name,
+            RAM_ROWS - 1
+        )));
+    }
+
+    if config.digit_rows.len() >= usize::from(COLON) {
+        return Err(InputError::new(format!(
+            "Too many digits for {}",
+            config.dev_name
+        )));
+    }
+
+    if config.brightness > 15 {
+        return Err(InputError::new(format!(
+            "Invalid brightness {} for {}, expected 0-15",
+            config.brightness, config.dev_name
+        )));
+    }
+
+    let mut display = HT16K33 {
+        dev_name: config.dev_name.clone(),
+        dev,
+        digit_rows: config.digit_rows.clone(),
+        colon_row: config.colon_row,
+        brightness: config.brightness,
+        rows: [0; RAM_ROWS],
+        needs_setup: true,
+    };
+
+    display.setup()?;
+    display.write_rows()?;
+    Ok(display)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segments, setup_ht16k33, COLON, DECIMAL_POINT};
+    use super::{DisplayConfig, DisplayModel};
+    use crate::input::bitevents::BitEvent;
+    use crate::input::emulator::EmulatedHT16K33;
+    use std::path::PathBuf;
+
+    fn event(bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from("clock"),
+            bit,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(segments(b'8'), Some(0x7F));
+        assert_eq!(segments(b'3' | DECIMAL_POINT), Some(0xCF));
+        assert_eq!(segments(b'?'), None);
+    }
+
+    #[test]
+    fn test_emulated_display() {
+        let chip = EmulatedHT16K33::new();
+        let config = DisplayConfig {
+            device_type: DisplayModel::Ht16k33,
+            dev_path: PathBuf::from("/dev/null"),
+            dev_name: String::from("clock"),
+            address: 0x70,
+            digit_rows: vec![0, 1, 3, 4],
+            colon_row: Some(2),
+            brightness: 8,
+        };
+
+        let mut display = setup_ht16k33(chip.clone(), &config).unwrap();
+        assert!(chip.is_on());
+        assert_eq!(chip.brightness(), 8);
+
+        display
+            .set_outputs(&[
+                event(0, b'1'),
+                event(1, b'2'),
+                event(COLON, 1),
+                event(2, b'0'),
+                event(3, b'5'),
+            ])
+            .unwrap();
+        assert_eq!(chip.rows(), [0x06, 0x5B, 0x02, 0x3F, 0x6D, 0, 0, 0]);
+
+        assert!(display.check_outputs(&[event(4, b'1')]).is_err());
+        assert!(display.check_outputs(&[event(0, b'?')]).is_err());
+    }
+}
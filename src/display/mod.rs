@@ -0,0 +1,225 @@
+use std::time::{Duration, Instant};
+
+use crate::input::bitevents::BitEvent;
+
+pub mod ht16k33;
+use self::ht16k33::COLON;
+
+/// Output events to show a number right aligned on a display. Anything too big to fit is shown
+/// as dashes
+pub fn number(dev_name: &str, digits: usize, value: i64) -> Vec<BitEvent> {
+    let mut text = value.to_string();
+    if text.len() > digits {
+        text = "-".repeat(digits);
+    }
+
+    let mut events = characters(dev_name, &format!("{:>width$}", text, width = digits));
+    events.push(colon(dev_name, false));
+    events
+}
+
+/// Output events to show a duration as minutes and seconds, or hours and minutes once it's past
+/// an hour, with the colon lit
+pub fn time(dev_name: &str, digits: usize, duration: Duration) -> Vec<BitEvent> {
+    let seconds = duration.as_secs();
+    let (major, minor) = if seconds < 3600 {
+        (seconds / 60, seconds % 60)
+    } else {
+        (seconds / 3600, seconds / 60 % 60)
+    };
+
+    let text = format!("{:02}{:02}", major, minor);
+    let text = if text.len() > digits {
+        "-".repeat(digits)
+    } else {
+        format!("{:>width$}", text, width = digits)
+    };
+
+    let mut events = characters(dev_name, &text);
+    events.push(colon(dev_name, true));
+    events
+}
+
+fn characters(dev_name: &str, text: &str) -> Vec<BitEvent> {
+    text.bytes()
+        .enumerate()
+        .map(|(position, character)| BitEvent {
+            dev_name: String::from(dev_name),
+            bit: position as u8,
+            value: character,
+        })
+        .collect()
+}
+
+fn colon(dev_name: &str, on: bool) -> BitEvent {
+    BitEvent {
+        dev_name: String::from(dev_name),
+        bit: COLON,
+        value: on as u8,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// Counting up, like mission elapsed time
+    Elapsed { since: Instant },
+    /// Counting down to zero, like the time to retrofire
+    Countdown { until: Instant },
+}
+
+struct Running {
+    dev_name: String,
+    digits: usize,
+    clock: Clock,
+    next: Instant,
+}
+
+/// Keeps clock displays up to date from the main loop, one clock per display
+pub struct Clocks {
+    running: Vec<Running>,
+}
+
+impl Clocks {
+    pub fn new() -> Clocks {
+        Clocks {
+            running: Vec::new(),
+        }
+    }
+
+    /// Run a clock on a display, replacing whatever clock it was showing
+    pub fn start(&mut self, dev_name: &str, digits: usize, clock: Clock, now: Instant) {
+        self.stop(dev_name);
+        self.running.push(Running {
+            dev_name: String::from(dev_name),
+            digits,
+            clock,
+            next: now,
+        });
+    }
+
+    /// Leave a display showing whatever it last showed
+    pub fn stop(&mut self, dev_name: &str) {
+        self.running.retain(|running| running.dev_name != dev_name);
+    }
+
+    /// Update every display whose clock has ticked over, returning the outputs to set
+    pub fn tick(&mut self, now: Instant) -> Vec<BitEvent> {
+        let mut events = Vec::new();
+
+        self.running.retain_mut(|running| {
+            if running.next > now {
+                return true;
+            }
+
+            let (shown, finished, next) = match running.clock {
+                Clock::Elapsed { since } => {
+                    let elapsed = now.saturating_duration_since(since);
+                    let into_second = Duration::from_nanos(elapsed.subsec_nanos().into());
+                    (elapsed, false, now - into_second + Duration::from_secs(1))
+                }
+                Clock::Countdown { until } => {
+                    let remaining = until.saturating_duration_since(now);
+                    let into_second = Duration::from_nanos(remaining.subsec_nanos().into());
+                    (remaining, remaining.is_zero(), now + into_second)
+                }
+            };
+
+            // Countdowns round up, so they only show zero once they get there
+            let shown = match running.clock {
+                Clock::Countdown { .. } if shown.subsec_nanos() > 0 => {
+                    Duration::from_secs(shown.as_secs() + 1)
+                }
+                _ => shown,
+            };
+
+            events.append(&mut time(&running.dev_name, running.digits, shown));
+            running.next = next;
+            !finished
+        });
+
+        events
+    }
+
+    /// When the next clock ticks over, if any are running
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.running.iter().map(|running| running.next).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ht16k33::COLON;
+    use super::{number, time, Clock, Clocks};
+    use crate::input::bitevents::BitEvent;
+    use std::time::{Duration, Instant};
+
+    // The characters and colon state shown by a batch of display events
+    fn shown(events: &[BitEvent]) -> (String, bool) {
+        let text = events
+            .iter()
+            .filter(|event| event.bit != COLON)
+            .map(|event| char::from(event.value))
+            .collect();
+        let colon = events
+            .iter()
+            .any(|event| event.bit == COLON && event.value != 0);
+        (text, colon)
+    }
+
+    #[test]
+    fn test_number() {
+        assert_eq!(
+            shown(&number("clock", 4, 42)),
+            (String::from("  42"), false)
+        );
+        assert_eq!(
+            shown(&number("clock", 4, -7)),
+            (String::from("  -7"), false)
+        );
+        assert_eq!(
+            shown(&number("clock", 4, 12345)),
+            (String::from("----"), false)
+        );
+    }
+
+    #[test]
+    fn test_time() {
+        assert_eq!(
+            shown(&time("clock", 4, Duration::from_secs(65))),
+            (String::from("0105"), true)
+        );
+        // Hours and minutes past the hour
+        assert_eq!(
+            shown(&time(
+                "clock",
+                4,
+                Duration::from_secs(2 * 3600 + 5 * 60 + 30)
+            )),
+            (String::from("0205"), true)
+        );
+    }
+
+    #[test]
+    fn test_countdown() {
+        let mut clocks = Clocks::new();
+        let start = Instant::now();
+        let until = start + Duration::from_millis(2500);
+
+        clocks.start("clock", 4, Clock::Countdown { until }, start);
+
+        assert_eq!(shown(&clocks.tick(start)), (String::from("0003"), true));
+        assert_eq!(
+            clocks.next_deadline(),
+            Some(start + Duration::from_millis(500))
+        );
+        assert_eq!(clocks.tick(start + Duration::from_millis(100)), vec![]);
+        assert_eq!(
+            shown(&clocks.tick(start + Duration::from_millis(500))),
+            (String::from("0002"), true)
+        );
+
+        // Stops once it gets to zero
+        assert_eq!(shown(&clocks.tick(until)), (String::from("0000"), true));
+        assert_eq!(clocks.next_deadline(), None);
+    }
+}
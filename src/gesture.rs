@@ -0,0 +1,365 @@
+use std::time::{Duration, Instant};
+
+use crate::aliases::Aliases;
+use crate::bindfiles::parse_duration;
+use crate::input::bitevents::BitEvent;
+use crate::input::InputError;
+
+// Timings for gestures that don't give their own
+const LONG_PRESS: Duration = Duration::from_millis(800);
+const DOUBLE_PRESS: Duration = Duration::from_millis(400);
+const CHORD: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    /// Held for at least `hold`. On once it has been, off on release
+    LongPress { input: (String, u8), hold: Duration },
+    /// Pressed a second time within `within` of the first press
+    DoublePress {
+        input: (String, u8),
+        within: Duration,
+    },
+    /// Every input pressed within `within` of each other. On once they all are, off when any of
+    /// them is released
+    Chord {
+        inputs: Vec<(String, u8)>,
+        within: Duration,
+    },
+}
+
+/// A gesture on one or more inputs, recognized as an event of its own. They are bound in the
+/// handler file with a device name of "<gesture>[@<timing>]:<target>", where the gesture is one
+/// of:
+///   long:<device> for a long press of the bit on the line, e.g. "long@1.5s:main_a"
+///   double:<device> for a double press of the bit on the line, e.g. "double:main_a"
+///   chord:<device>:<bit>+<device>:<bit>... for pressing several at once, any bit on the line
+/// Inputs can also be given by alias, in which case the bit on the line doesn't matter for long
+/// and double presses either
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gesture {
+    // The event to fire, the device name and bit it was bound with
+    event: (String, u8),
+    kind: Kind,
+}
+
+impl Gesture {
+    /// Parse the gesture bound to a handler file key, None if the key is for a plain input
+    pub fn parse(
+        dev_name: &str,
+        bit: u8,
+        aliases: &Aliases,
+    ) -> Option<Result<Gesture, InputError>> {
+        let (spec, target) = dev_name.split_once(':')?;
+        let (name, timing) = match spec.split_once('@') {
+            Some((name, timing)) => (name, Some(timing)),
+            None => (spec, None),
+        };
+
+        let timing_or = |default: Duration| -> Result<Duration, InputError> {
+            timing.map_or(Ok(default), parse_duration)
+        };
+        let input = || {
+            aliases
+                .get(target)
+                .cloned()
+                .unwrap_or_else(|| (String::from(target), bit))
+        };
+
+        let kind = match name {
+            "long" => timing_or(LONG_PRESS).map(|hold| Kind::LongPress {
+                input: input(),
+                hold,
+            }),
+            "double" => timing_or(DOUBLE_PRESS).map(|within| Kind::DoublePress {
+                input: input(),
+                within,
+            }),
+            "chord" => timing_or(CHORD).and_then(|within| {
+                let inputs = target
+                    .split('+')
+                    .map(|input| aliases.resolve(input))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if inputs.len() < 2 {
+                    return Err(InputError::new(format!(
+                        "A chord needs at least two inputs: {}",
+                        dev_name
+                    )));
+                }
+                Ok(Kind::Chord { inputs, within })
+            }),
+            // Not a gesture, just a device name with a colon in it
+            _ => return None,
+        };
+
+        Some(kind.map(|kind| Gesture {
+            event: (String::from(dev_name), bit),
+            kind,
+        }))
+    }
+
+    fn fire(&self, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: self.event.0.clone(),
+            bit: self.event.1,
+            value,
+        }
+    }
+}
+
+struct Tracker {
+    gesture: Gesture,
+    // When each of the gesture's inputs was pressed, if it is down (or for a double press, was
+    // pressed once already)
+    pressed: Vec<Option<Instant>>,
+    // Whether the gesture is on, for those that turn off again
+    fired: bool,
+}
+
+impl Tracker {
+    fn inputs(&self) -> &[(String, u8)] {
+        match self.gesture.kind {
+            Kind::LongPress { ref input, .. } | Kind::DoublePress { ref input, .. } => {
+                std::slice::from_ref(input)
+            }
+            Kind::Chord { ref inputs, .. } => inputs,
+        }
+    }
+
+    fn process(&mut self, event: &BitEvent, now: Instant) -> Option<BitEvent> {
+        let index = self
+            .inputs()
+            .iter()
+            .position(|(dev_name, bit)| *dev_name == event.dev_name && *bit == event.bit)?;
+        let pressed = event.value != 0;
+
+        match self.gesture.kind {
+            Kind::LongPress { .. } => {
+                self.pressed[index] = if pressed { Some(now) } else { None };
+                self.release(pressed)
+            }
+            Kind::DoublePress { within, .. } if pressed => match self.pressed[index] {
+                Some(first) if now.saturating_duration_since(first) <= within => {
+                    self.pressed[index] = None;
+                    Some(self.gesture.fire(1))
+                }
+                _ => {
+                    self.pressed[index] = Some(now);
+                    None
+                }
+            },
+            Kind::DoublePress { .. } => None,
+            Kind::Chord { within, .. } => {
+                self.pressed[index] = if pressed { Some(now) } else { None };
+
+                let times: Option<Vec<Instant>> = self.pressed.iter().copied().collect();
+                match times {
+                    Some(times) if !self.fired => {
+                        let first = times.iter().min()?;
+                        let last = times.iter().max()?;
+                        if last.saturating_duration_since(*first) <= within {
+                            self.fired = true;
+                            return Some(self.gesture.fire(1));
+                        }
+                        None
+                    }
+                    _ => self.release(pressed),
+                }
+            }
+        }
+    }
+
+    // Turn the gesture off once an input is let go
+    fn release(&mut self, pressed: bool) -> Option<BitEvent> {
+        if !pressed && self.fired {
+            self.fired = false;
+            Some(self.gesture.fire(0))
+        } else {
+            None
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self.gesture.kind {
+            Kind::LongPress { hold, .. } if !self.fired => self.pressed[0].map(|at| at + hold),
+            _ => None,
+        }
+    }
+}
+
+/// Recognizes gestures in the stream of input events. Plain events still go to their own
+/// handlers, the gestures they make up are extra events
+pub struct Gestures {
+    trackers: Vec<Tracker>,
+}
+
+impl Gestures {
+    pub fn new(gestures: Vec<Gesture>) -> Gestures {
+        Gestures {
+            trackers: gestures
+                .into_iter()
+                .map(|gesture| {
+                    let mut tracker = Tracker {
+                        gesture,
+                        pressed: Vec::new(),
+                        fired: false,
+                    };
+                    tracker.pressed = vec![None; tracker.inputs().len()];
+                    tracker
+                })
+                .collect(),
+        }
+    }
+
+    /// Track a batch of input events, returning any gestures they complete
+    pub fn process(&mut self, events: &[BitEvent], now: Instant) -> Vec<BitEvent> {
+        events
+            .iter()
+            .flat_map(|event| {
+                self.trackers
+                    .iter_mut()
+                    .filter_map(|tracker| tracker.process(event, now))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Fire any long presses that have been held long enough
+    pub fn tick(&mut self, now: Instant) -> Vec<BitEvent> {
+        self.trackers
+            .iter_mut()
+            .filter(|tracker| tracker.deadline().is_some_and(|deadline| deadline <= now))
+            .map(|tracker| {
+                tracker.fired = true;
+                tracker.gesture.fire(1)
+            })
+            .collect()
+    }
+
+    /// When the next long press is due, if anything is being held
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.trackers.iter().filter_map(Tracker::deadline).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gesture, Gestures};
+    use crate::aliases::Aliases;
+    use crate::input::bitevents::BitEvent;
+    use std::time::{Duration, Instant};
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    fn gestures(keys: &[(&str, u8)]) -> Gestures {
+        Gestures::new(
+            keys.iter()
+                .map(|&(dev_name, bit)| {
+                    Gesture::parse(dev_name, bit, &Aliases::default())
+                        .unwrap()
+                        .unwrap()
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        let aliases = Aliases::parse("abort_handle = main_c:7").unwrap();
+        let parse = |dev_name, bit| Gesture::parse(dev_name, bit, &aliases);
+
+        assert_eq!(parse("main_a", 3), None);
+        assert_eq!(parse("other:main_a", 3), None);
+        assert!(parse("long@1.5s:main_a", 3).unwrap().is_ok());
+        assert!(parse("long@soon:main_a", 3).unwrap().is_err());
+        assert!(parse("chord:main_a:1+main_b:2", 0).unwrap().is_ok());
+        assert!(parse("chord:main_a:1", 0).unwrap().is_err());
+        assert!(parse("chord:main_a+main_b", 0).unwrap().is_err());
+
+        // Aliases stand in for the device and bit
+        assert!(parse("chord:abort_handle+main_b:2", 0).unwrap().is_ok());
+        assert_eq!(
+            parse("long:abort_handle", 0).unwrap(),
+            parse("long:main_c", 7).unwrap().map(|mut gesture| {
+                gesture.event = (String::from("long:abort_handle"), 0);
+                gesture
+            })
+        );
+    }
+
+    #[test]
+    fn test_long_press() {
+        let mut gestures = gestures(&[("long@1s:main_a", 3)]);
+        let start = Instant::now();
+
+        // Let go too soon
+        assert_eq!(gestures.process(&[event("main_a", 3, 1)], start), vec![]);
+        assert_eq!(
+            gestures.next_deadline(),
+            Some(start + Duration::from_secs(1))
+        );
+        assert_eq!(gestures.process(&[event("main_a", 3, 0)], start), vec![]);
+        assert_eq!(gestures.next_deadline(), None);
+
+        gestures.process(&[event("main_a", 3, 1)], start);
+        assert_eq!(gestures.tick(start + Duration::from_millis(500)), vec![]);
+        assert_eq!(
+            gestures.tick(start + Duration::from_secs(1)),
+            vec![event("long@1s:main_a", 3, 1)]
+        );
+        assert_eq!(gestures.next_deadline(), None);
+        assert_eq!(
+            gestures.process(&[event("main_a", 3, 0)], start + Duration::from_secs(2)),
+            vec![event("long@1s:main_a", 3, 0)]
+        );
+    }
+
+    #[test]
+    fn test_double_press() {
+        let mut gestures = gestures(&[("double:main_a", 3)]);
+        let start = Instant::now();
+        let press = |gestures: &mut Gestures, millis| {
+            let at = start + Duration::from_millis(millis);
+            let mut events = gestures.process(&[event("main_a", 3, 1)], at);
+            events.append(&mut gestures.process(&[event("main_a", 3, 0)], at));
+            events
+        };
+
+        assert_eq!(press(&mut gestures, 0), vec![]);
+        assert_eq!(
+            press(&mut gestures, 300),
+            vec![event("double:main_a", 3, 1)]
+        );
+        // A third press starts over
+        assert_eq!(press(&mut gestures, 500), vec![]);
+        assert_eq!(press(&mut gestures, 1000), vec![]);
+    }
+
+    #[test]
+    fn test_chord() {
+        let mut gestures = gestures(&[("chord:main_a:1+main_b:2", 0)]);
+        let start = Instant::now();
+
+        gestures.process(&[event("main_a", 1, 1)], start);
+        assert_eq!(
+            gestures.process(&[event("main_b", 2, 1)], start + Duration::from_millis(100)),
+            vec![event("chord:main_a:1+main_b:2", 0, 1)]
+        );
+        assert_eq!(
+            gestures.process(&[event("main_a", 1, 0)], start),
+            vec![event("chord:main_a:1+main_b:2", 0, 0)]
+        );
+
+        // Too far apart
+        assert_eq!(
+            gestures.process(&[event("main_a", 1, 1)], start + Duration::from_secs(1)),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,494 @@
+use i2cdev::core::I2CDevice;
+use serde::Deserialize;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::bitevents::BitEvent;
+use super::InputError;
+
+// Registers. Both are big endian, unlike SMBus words
+const CONVERSION: u8 = 0x00;
+const CONFIG: u8 = 0x01;
+
+// CONFIG bits
+const CONFIG_OS: u16 = 0x8000;
+const CONFIG_MUX_SINGLE: u16 = 0x4000;
+const CONFIG_MODE_SINGLE: u16 = 0x0100;
+// 128 SPS on the ADS1115, 1600 SPS on the ADS1015
+const CONFIG_DR_DEFAULT: u16 = 0x0080;
+const CONFIG_COMP_DISABLE: u16 = 0x0003;
+
+// Full scale voltages, in PGA order
+const RANGES: [f64; 6] = [6.144, 4.096, 2.048, 1.024, 0.512, 0.256];
+
+// How many times to check for a finished conversion before giving up
+const CONVERSION_ATTEMPTS: usize = 5;
+
+// How long a failing ADC is left before it is tried again, at the least
+const RETRY_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AdcModel {
+    // 16 bit
+    Ads1115,
+    // 12 bit
+    Ads1015,
+}
+
+/// An ADS1115/ADS1015 analog to digital converter. Each channel reports its reading scaled to
+/// 0..=full_scale as the value of "<dev_name>:<channel>" events
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct AdcConfig {
+    pub device_type: AdcModel,
+    pub dev_path: PathBuf,
+    pub dev_name: String,
+    pub address: u16,
+    #[serde(default = "default_adc_poll_ms")]
+    pub poll_ms: u64,
+    pub channels: Vec<ChannelConfig>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct ChannelConfig {
+    // AIN0-AIN3, measured single ended against ground
+    pub channel: u8,
+    // Programmable gain, as the full scale voltage: 6.144, 4.096, 2.048, 1.024, 0.512 or 0.256
+    #[serde(default = "default_range_volts")]
+    pub range_volts: f64,
+    // The voltages that scale to 0 and full_scale
+    #[serde(default)]
+    pub min_volts: f64,
+    pub max_volts: f64,
+    #[serde(default = "default_full_scale")]
+    pub full_scale: u8,
+    // Only report when the scaled value crosses one of these. Without thresholds every change of
+    // at least `hysteresis` is reported
+    #[serde(default)]
+    pub thresholds: Vec<u8>,
+    // Also how far below a threshold the value must fall before it counts as crossed again
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: u8,
+}
+
+fn default_adc_poll_ms() -> u64 {
+    100
+}
+
+fn default_range_volts() -> f64 {
+    4.096
+}
+
+fn default_full_scale() -> u8 {
+    255
+}
+
+fn default_hysteresis() -> u8 {
+    2
+}
+
+struct Channel {
+    config: ChannelConfig,
+    pga: u16,
+    reported: Option<u8>,
+}
+
+pub struct ADS1x15<D: I2CDevice> {
+    dev_name: String,
+    dev: D,
+    model: AdcModel,
+    poll_time: Duration,
+    channels: Vec<Channel>,
+}
+
+impl<D> ADS1x15<D>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    /// Take a reading of every channel, returning events for those that should be reported
+    pub fn poll_channels(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        let mut events = Vec::new();
+
+        for index in 0..self.channels.len() {
+            let volts = self.read_channel(index)?;
+            let channel = &mut self.channels[index];
+            let value = scale(&channel.config, volts);
+
+            debug!(
+                "{}:{} read {:.3}V, scaled to {}",
+                self.dev_name, channel.config.channel, volts, value
+            );
+
+            if let Some(reported) = next_report(&channel.config, channel.reported, value) {
+                channel.reported = Some(reported);
+                events.push(BitEvent {
+                    dev_name: self.dev_name.clone(),
+                    bit: channel.config.channel,
+                    value: reported,
+                });
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// The last reported value of every channel
+    pub fn current_state(&self) -> Vec<BitEvent> {
+        self.channels
+            .iter()
+            .filter_map(|channel| {
+                channel.reported.map(|value| BitEvent {
+                    dev_name: self.dev_name.clone(),
+                    bit: channel.config.channel,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Perform a single shot conversion on one channel, returning the voltage
+    fn read_channel(&mut self, index: usize) -> Result<f64, InputError> {
+        let channel = &self.channels[index];
+        let config = CONFIG_OS
+            | CONFIG_MUX_SINGLE
+            | u16::from(channel.config.channel) << 12
+            | channel.pga << 9
+            | CONFIG_MODE_SINGLE
+            | CONFIG_DR_DEFAULT
+            | CONFIG_COMP_DISABLE;
+        let range = channel.config.range_volts;
+
+        self.dev
+            .smbus_write_word_data(CONFIG, config.swap_bytes())?;
+
+        let mut attempts = 0;
+        loop {
+            thread::sleep(conversion_time(self.model));
+
+            // OS reads back as set once the conversion is done
+            let status = self.dev.smbus_read_word_data(CONFIG)?.swap_bytes();
+            if status & CONFIG_OS != 0 {
+                break;
+            }
+
+            attempts += 1;
+            if attempts == CONVERSION_ATTEMPTS {
+                return Err(InputError::new(format!(
+                    "Timed out waiting for conversion on {}:{}",
+                    self.dev_name, self.channels[index].config.channel
+                )));
+            }
+        }
+
+        let raw = self.dev.smbus_read_word_data(CONVERSION)?.swap_bytes() as i16;
+        Ok(to_volts(self.model, raw, range))
+    }
+}
+
+fn conversion_time(model: AdcModel) -> Duration {
+    match model {
+        AdcModel::Ads1115 => Duration::from_millis(8),
+        AdcModel::Ads1015 => Duration::from_millis(1),
+    }
+}
+
+fn to_volts(model: AdcModel, raw: i16, range: f64) -> f64 {
+    match model {
+        AdcModel::Ads1115 => f64::from(raw) * range / 32768.0,
+        // 12 bit result, left justified
+        AdcModel::Ads1015 => f64::from(raw >> 4) * range / 2048.0,
+    }
+}
+
+fn scale(config: &ChannelConfig, volts: f64) -> u8 {
+    let fraction = (volts - config.min_volts) / (config.max_volts - config.min_volts);
+    (fraction.clamp(0.0, 1.0) * f64::from(config.full_scale)).round() as u8
+}
+
+/// How many thresholds the value is past, given the band it was in before. A value has to drop
+/// `hysteresis` below a threshold before it counts as back under it, so a pot resting on a
+/// threshold doesn't chatter
+fn band(config: &ChannelConfig, previous_band: usize, value: u8) -> usize {
+    let rising = config.thresholds.iter().filter(|&&t| value >= t).count();
+    let falling = config
+        .thresholds
+        .iter()
+        .filter(|&&t| value >= t.saturating_sub(config.hysteresis))
+        .count();
+
+    if rising > previous_band {
+        rising
+    } else if falling < previous_band {
+        falling
+    } else {
+        previous_band
+    }
+}
+
+/// Work out whether a new reading should be reported. The first reading only sets the baseline
+fn next_report(config: &ChannelConfig, reported: Option<u8>, value: u8) -> Option<u8> {
+    let previous = match reported {
+        Some(previous) => previous,
+        None => return Some(value),
+    };
+
+    let changed = if config.thresholds.is_empty() {
+        value.abs_diff(previous) >= config.hysteresis.max(1)
+    } else {
+        let previous_band = config.thresholds.iter().filter(|&&t| previous >= t).count();
+        band(config, previous_band, value) != previous_band
+    };
+
+    if changed {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Validate the config for an already opened ADC and take a baseline reading
+pub fn setup_ads1x15<D>(dev: D, config: &AdcConfig) -> Result<ADS1x15<D>, InputError>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    let mut channels = Vec::with_capacity(config.channels.len());
+
+    for channel in &config.channels {
+        if channel.channel > 3 {
+            return Err(InputError::new(format!(
+                "Invalid channel {} for {}, expected 0-3",
+                channel.channel, config.dev_name
+            )));
+        }
+
+        if channel.max_volts <= channel.min_volts {
+            return Err(InputError::new(format!(
+                "max_volts must be above min_volts for {}:{}",
+                config.dev_name, channel.channel
+            )));
+        }
+
+        let pga = RANGES
+            .iter()
+            .position(|&range| (range - channel.range_volts).abs() < 0.001)
+            .ok_or_else(|| {
+                InputError::new(format!(
+                    "Invalid range {}V for {}:{}, expected one of {:?}",
+                    channel.range_volts, config.dev_name, channel.channel, RANGES
+                ))
+            })?;
+
+        channels.push(Channel {
+            config: channel.clone(),
+            pga: pga as u16,
+            reported: None,
+        });
+    }
+
+    let mut adc = ADS1x15 {
+        dev_name: config.dev_name.clone(),
+        dev,
+        model: config.device_type,
+        poll_time: Duration::from_millis(config.poll_ms),
+        channels,
+    };
+
+    adc.poll_channels()?;
+    Ok(adc)
+}
+
+/// An ADC polled by its own thread, sending into the same channel as the expander buses
+pub struct AdcPoller<D: I2CDevice> {
+    dev_name: String,
+    // Shared with the poller, which only holds the lock while taking readings
+    pub adc: Arc<Mutex<ADS1x15<D>>>,
+    poller: JoinHandle<()>,
+    poll_condition: Arc<AtomicBool>,
+}
+
+impl<D> AdcPoller<D>
+where
+    D: I2CDevice + Send + 'static,
+    InputError: From<D::Error>,
+{
+    pub fn start(adc: ADS1x15<D>, tx: Sender<Result<Vec<BitEvent>, InputError>>) -> AdcPoller<D> {
+        let dev_name = adc.dev_name.clone();
+        let poll_time = adc.poll_time;
+        let adc = Arc::new(Mutex::new(adc));
+        let poll_condition = Arc::new(AtomicBool::new(true));
+
+        let poller = {
+            let adc = adc.clone();
+            let should_run = poll_condition.clone();
+
+            thread::spawn(move || {
+                while should_run.load(Ordering::Relaxed) {
+                    let result = adc.lock().unwrap().poll_channels();
+                    let (result, wait) = match result {
+                        Ok(events) if events.is_empty() => (None, poll_time),
+                        Ok(events) => (Some(Ok(events)), poll_time),
+                        // Don't hammer a failing device
+                        Err(err) => (Some(Err(err)), RETRY_TIME.max(poll_time)),
+                    };
+
+                    if let Some(result) = result {
+                        if tx.send(result).is_err() {
+                            // The handler is gone, nobody is listening any more
+                            return;
+                        }
+                    }
+
+                    thread::sleep(wait);
+                }
+            })
+        };
+
+        AdcPoller {
+            dev_name,
+            adc,
+            poller,
+            poll_condition,
+        }
+    }
+}
+
+impl<D: I2CDevice> AdcPoller<D> {
+    pub fn shutdown(self) {
+        debug!("Shutting down poller for {}", self.dev_name);
+
+        self.poll_condition.store(false, Ordering::Relaxed);
+        if self.poller.join().is_err() {
+            error!("Poller for {} panicked", self.dev_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_report, scale, setup_ads1x15, to_volts};
+    use super::{AdcConfig, AdcModel, ChannelConfig};
+    use crate::input::bitevents::BitEvent;
+    use crate::input::emulator::EmulatedADS1x15;
+    use std::path::PathBuf;
+
+    fn channel_config(thresholds: Vec<u8>, hysteresis: u8) -> ChannelConfig {
+        ChannelConfig {
+            channel: 0,
+            range_volts: 4.096,
+            min_volts: 0.0,
+            max_volts: 3.3,
+            full_scale: 100,
+            thresholds,
+            hysteresis,
+        }
+    }
+
+    #[test]
+    fn test_to_volts() {
+        assert_eq!(to_volts(AdcModel::Ads1115, 16384, 4.096), 2.048);
+        assert_eq!(to_volts(AdcModel::Ads1115, -32768, 4.096), -4.096);
+        // 0x400 in the top 12 bits
+        assert_eq!(to_volts(AdcModel::Ads1015, 0x4000, 4.096), 2.048);
+    }
+
+    #[test]
+    fn test_scale() {
+        let config = channel_config(vec![], 2);
+
+        assert_eq!(scale(&config, 1.65), 50);
+        assert_eq!(scale(&config, 3.3), 100);
+        // Out of range readings are clamped
+        assert_eq!(scale(&config, -0.1), 0);
+        assert_eq!(scale(&config, 4.0), 100);
+    }
+
+    #[test]
+    fn test_hysteresis_reports() {
+        let config = channel_config(vec![], 3);
+
+        assert_eq!(next_report(&config, None, 40), Some(40));
+        assert_eq!(next_report(&config, Some(40), 42), None);
+        assert_eq!(next_report(&config, Some(40), 37), Some(37));
+        assert_eq!(next_report(&config, Some(40), 43), Some(43));
+    }
+
+    #[test]
+    fn test_threshold_reports() {
+        let config = channel_config(vec![25, 50, 75], 2);
+
+        assert_eq!(next_report(&config, Some(20), 24), None);
+        assert_eq!(next_report(&config, Some(20), 25), Some(25));
+        // Moving within a band
+        assert_eq!(next_report(&config, Some(25), 40), None);
+        // Dipping just under the threshold isn't enough to fall back
+        assert_eq!(next_report(&config, Some(25), 24), None);
+        assert_eq!(next_report(&config, Some(25), 22), Some(22));
+        // Jumping across several thresholds is one report
+        assert_eq!(next_report(&config, Some(22), 80), Some(80));
+    }
+
+    #[test]
+    fn test_emulated_adc() {
+        let chip = EmulatedADS1x15::new(AdcModel::Ads1115);
+        chip.set_volts(0, 1.65);
+        chip.set_volts(2, 3.3);
+
+        let mut config = AdcConfig {
+            device_type: AdcModel::Ads1115,
+            dev_path: PathBuf::from("/dev/null"),
+            dev_name: String::from("throttle"),
+            address: 0x48,
+            poll_ms: 100,
+            channels: vec![
+                channel_config(vec![], 3),
+                ChannelConfig {
+                    channel: 2,
+                    ..channel_config(vec![], 3)
+                },
+            ],
+        };
+
+        // The baseline is read at setup
+        let mut adc = setup_ads1x15(chip.clone(), &config).unwrap();
+        assert_eq!(
+            adc.current_state(),
+            vec![
+                BitEvent {
+                    dev_name: String::from("throttle"),
+                    bit: 0,
+                    value: 50,
+                },
+                BitEvent {
+                    dev_name: String::from("throttle"),
+                    bit: 2,
+                    value: 100,
+                }
+            ]
+        );
+
+        // Within the hysteresis
+        chip.set_volts(0, 1.7);
+        assert_eq!(adc.poll_channels(), Ok(vec![]));
+
+        chip.set_volts(0, 0.33);
+        assert_eq!(
+            adc.poll_channels(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("throttle"),
+                bit: 0,
+                value: 10,
+            }])
+        );
+
+        config.channels[0].range_volts = 5.0;
+        assert!(setup_ads1x15(chip, &config).is_err());
+    }
+}
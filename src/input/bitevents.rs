@@ -1,8 +1,11 @@
 use std::fmt;
 use std::fmt::Error;
 use std::fmt::Formatter;
+use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+use super::InputError;
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct BitEvent {
     pub dev_name: String,
     pub bit: u8,
@@ -19,6 +22,30 @@ impl fmt::Display for BitEvent {
     }
 }
 
+/// Parses a single "<dev>:<bit>:<value>" spec
+impl FromStr for BitEvent {
+    type Err = InputError;
+
+    fn from_str(s: &str) -> Result<BitEvent, InputError> {
+        debug!("Parsing '{}'", s);
+        let parts = s.split(':').collect::<Vec<_>>();
+        if parts.len() == 3 {
+            Ok(BitEvent {
+                dev_name: String::from(parts[0]),
+                bit: parts[1].parse()?,
+                value: parts[2].parse()?,
+            })
+        } else {
+            Err(InputError::new(format!("Invalid input spec: '{}'", s)))
+        }
+    }
+}
+
+/// Parses a comma-separated list of event specs, e.g. "main_a:1:1,main_b:3:0"
+pub fn parse_bit_events(input: &str) -> Result<Vec<BitEvent>, InputError> {
+    input.split(',').map(BitEvent::from_str).collect()
+}
+
 pub fn bit_compare(dev_name: &str, previous: u16, current: u16) -> Vec<BitEvent> {
     let mut events: Vec<BitEvent> = Vec::new();
 
@@ -57,7 +84,7 @@ fn bit_value(data: u16, shift: u8) -> u8 {
 
 #[cfg(test)]
 mod tests {
-    use crate::input::bitevents::{bit_compare, BitEvent};
+    use crate::input::bitevents::{bit_compare, parse_bit_events, BitEvent};
 
     #[test]
     fn confirm_basic_event() {
@@ -92,4 +119,30 @@ mod tests {
                 )
         );
     }
+
+    #[test]
+    fn test_parse_events() {
+        assert_eq!(
+            parse_bit_events("main_a:1:1,main_b:12:0"),
+            Ok(vec![
+                BitEvent {
+                    dev_name: String::from("main_a"),
+                    bit: 1,
+                    value: 1
+                },
+                BitEvent {
+                    dev_name: String::from("main_b"),
+                    bit: 12,
+                    value: 0
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_events() {
+        assert!(parse_bit_events("main_a:1").is_err());
+        assert!(parse_bit_events("main_a:one:1").is_err());
+        assert!(parse_bit_events("main_a:1:1,").is_err());
+    }
 }
@@ -0,0 +1,357 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::bitevents::BitEvent;
+use super::*;
+
+// How long a reader leaves a failing backend before reading it again
+const RETRY_TIME: Duration = Duration::from_millis(100);
+
+type Reply<T> = Sender<Result<T, InputError>>;
+
+// Requests for a backend, run by its reader thread between reads. The read in progress is woken
+// up for them
+enum Command {
+    CheckOutput(Vec<BitEvent>, Reply<()>),
+    SetOutput(Vec<BitEvent>, Reply<()>),
+    CurrentState(Reply<Vec<BitEvent>>),
+}
+
+struct Backend {
+    name: String,
+    devices: Vec<String>,
+    commands: Sender<Command>,
+    waker: Waker,
+    reader: JoinHandle<()>,
+    should_run: Arc<AtomicBool>,
+}
+
+impl Backend {
+    fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, InputError> {
+        let (tx, rx) = channel();
+
+        self.commands
+            .send(command(tx))
+            .map_err(|_| InputError::new(format!("{} has stopped", self.name)))?;
+        self.waker.wake();
+
+        rx.recv()
+            .map_err(|_| InputError::new(format!("{} has stopped", self.name)))?
+    }
+}
+
+/// Runs any number of backends at once, merging their events. Each backend gets its own reader
+/// thread, and outputs are set by whichever backend drives the target device.
+pub struct CompositeInput {
+    backends: Vec<Backend>,
+    tx: Sender<Result<Vec<BitEvent>, InputError>>,
+    rx: Receiver<Result<Vec<BitEvent>, InputError>>,
+}
+
+impl CompositeInput {
+    pub fn new() -> CompositeInput {
+        let (tx, rx) = channel();
+
+        CompositeInput {
+            backends: Vec::new(),
+            tx,
+            rx,
+        }
+    }
+
+    pub fn add<T: InputHandler + Send + 'static>(&mut self, name: &str, mut input: T) {
+        let devices = input.output_devices();
+        let waker = input.waker();
+        let should_run = Arc::new(AtomicBool::new(true));
+        let (commands, command_rx) = channel();
+
+        debug!(
+            "Adding {} to composite input, with outputs {:?}",
+            name, devices
+        );
+
+        let reader = {
+            let name = String::from(name);
+            let should_run = should_run.clone();
+            let tx = self.tx.clone();
+
+            thread::spawn(move || {
+                while should_run.load(Ordering::Relaxed) {
+                    for command in command_rx.try_iter() {
+                        match command {
+                            Command::CheckOutput(bits, reply) => {
+                                let _ = reply.send(input.check_output(&bits));
+                            }
+                            Command::SetOutput(bits, reply) => {
+                                let _ = reply.send(input.set_output(&bits));
+                            }
+                            Command::CurrentState(reply) => {
+                                let _ = reply.send(input.current_state());
+                            }
+                        }
+                    }
+
+                    match input.read_events() {
+                        // Woken up, for a command or to stop
+                        Ok(ref events) if events.is_empty() => {}
+                        Ok(events) => {
+                            if tx.send(Ok(events)).is_err() {
+                                // The composite is gone, nobody is listening any more
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            if tx.send(Err(err)).is_err() {
+                                break;
+                            }
+                            // Don't spin on a backend that keeps failing
+                            thread::sleep(RETRY_TIME);
+                        }
+                    }
+                }
+
+                debug!("Shutting down {}", name);
+                input.shutdown();
+            })
+        };
+
+        self.backends.push(Backend {
+            name: String::from(name),
+            devices,
+            commands,
+            waker,
+            reader,
+            should_run,
+        });
+    }
+
+    /// Split a batch of outputs up by the backend (as an index) that drives each device
+    fn split_outputs(&self, bits: &[BitEvent]) -> Result<Vec<(usize, Vec<BitEvent>)>, InputError> {
+        let mut batches: Vec<(usize, Vec<BitEvent>)> = Vec::new();
+
+        for event in bits {
+            let index = self
+                .backends
+                .iter()
+                .position(|backend| backend.devices.contains(&event.dev_name))
+                .ok_or_else(|| {
+                    InputError::new(format!("No input drives outputs on {}", event.dev_name))
+                })?;
+
+            match batches.iter_mut().find(|(existing, _)| *existing == index) {
+                Some((_, batch)) => batch.push(event.clone()),
+                None => batches.push((index, vec![event.clone()])),
+            }
+        }
+
+        Ok(batches)
+    }
+}
+
+impl InputHandler for CompositeInput {
+    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        self.rx
+            .recv()
+            .unwrap_or_else(|_| Err(InputError::from_str("All inputs have stopped")))
+    }
+
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Ok(vec![]),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(InputError::from_str("All inputs have stopped"))
+            }
+        }
+    }
+
+    fn waker(&self) -> Waker {
+        let tx = self.tx.clone();
+        Waker::new(move || {
+            let _ = tx.send(Ok(Vec::new()));
+        })
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        // Every backend has to be able to take its share before any of them writes theirs
+        let batches = self.split_outputs(bits)?;
+
+        for (index, batch) in &batches {
+            self.backends[*index].request(|reply| Command::CheckOutput(batch.clone(), reply))?;
+        }
+
+        for (index, batch) in batches {
+            self.backends[index].request(|reply| Command::SetOutput(batch, reply))?;
+        }
+
+        Ok(())
+    }
+
+    fn check_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        for (index, batch) in self.split_outputs(bits)? {
+            self.backends[index].request(|reply| Command::CheckOutput(batch, reply))?;
+        }
+
+        Ok(())
+    }
+
+    fn output_devices(&self) -> Vec<String> {
+        self.backends
+            .iter()
+            .flat_map(|backend| backend.devices.iter().cloned())
+            .collect()
+    }
+
+    fn current_state(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        let mut events = Vec::new();
+
+        for backend in &self.backends {
+            events.append(&mut backend.request(Command::CurrentState)?);
+        }
+
+        Ok(events)
+    }
+
+    fn shutdown(self) {
+        for backend in self.backends {
+            backend.should_run.store(false, Ordering::Relaxed);
+            backend.waker.wake();
+            if backend.reader.join().is_err() {
+                error!("Reader for {} panicked", backend.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompositeInput;
+    use crate::input::bitevents::BitEvent;
+    use crate::input::{InputError, InputHandler, Waker};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    // Reads whatever the test feeds it, and reports the outputs it is asked to set
+    struct FakeInput {
+        devices: Vec<String>,
+        events_tx: Sender<Vec<BitEvent>>,
+        events: Receiver<Vec<BitEvent>>,
+        outputs: Sender<Vec<BitEvent>>,
+    }
+
+    fn fake_input(devices: &[&str]) -> (FakeInput, Sender<Vec<BitEvent>>, Receiver<Vec<BitEvent>>) {
+        let (events_tx, events) = channel();
+        let (outputs, outputs_rx) = channel();
+
+        let input = FakeInput {
+            devices: devices.iter().map(|&name| String::from(name)).collect(),
+            events_tx: events_tx.clone(),
+            events,
+            outputs,
+        };
+
+        (input, events_tx, outputs_rx)
+    }
+
+    impl InputHandler for FakeInput {
+        fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+            Ok(self.events.recv().unwrap_or_default())
+        }
+
+        fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+            Ok(self.events.recv_timeout(timeout).unwrap_or_default())
+        }
+
+        fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+            self.check_output(bits)?;
+            self.outputs.send(bits.to_vec()).unwrap();
+            Ok(())
+        }
+
+        // Like an expander, with 16 pins
+        fn check_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+            match bits.iter().find(|event| event.bit >= 16) {
+                Some(event) => Err(InputError::new(format!("No pin {}", event.bit))),
+                None => Ok(()),
+            }
+        }
+
+        fn output_devices(&self) -> Vec<String> {
+            self.devices.clone()
+        }
+
+        fn waker(&self) -> Waker {
+            let tx = self.events_tx.clone();
+            Waker::new(move || {
+                let _ = tx.send(vec![]);
+            })
+        }
+
+        fn shutdown(self) {}
+    }
+
+    #[test]
+    fn test_merge_events() {
+        let (panel, panel_events, _) = fake_input(&["main_a"]);
+        let (stdin, stdin_events, _) = fake_input(&[]);
+
+        let mut composite = CompositeInput::new();
+        composite.add("panel", panel);
+        composite.add("stdin", stdin);
+
+        panel_events.send(vec![event("main_a", 1, 1)]).unwrap();
+        assert_eq!(composite.read_events(), Ok(vec![event("main_a", 1, 1)]));
+
+        // Virtual inputs can be poked for a real device
+        stdin_events.send(vec![event("main_a", 2, 1)]).unwrap();
+        assert_eq!(composite.read_events(), Ok(vec![event("main_a", 2, 1)]));
+
+        composite.shutdown();
+    }
+
+    #[test]
+    fn test_route_outputs() {
+        let (first, _first_events, first_outputs) = fake_input(&["main_a", "main_b"]);
+        let (second, _second_events, second_outputs) = fake_input(&["upper_a"]);
+
+        let mut composite = CompositeInput::new();
+        composite.add("first", first);
+        composite.add("second", second);
+
+        composite
+            .set_output(&[
+                event("upper_a", 0, 1),
+                event("main_a", 3, 1),
+                event("main_b", 4, 0),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            first_outputs.recv(),
+            Ok(vec![event("main_a", 3, 1), event("main_b", 4, 0)])
+        );
+        assert_eq!(second_outputs.recv(), Ok(vec![event("upper_a", 0, 1)]));
+
+        assert!(composite.set_output(&[event("nowhere", 0, 1)]).is_err());
+
+        // Nothing is written when any backend would turn its share down
+        assert!(composite
+            .set_output(&[event("main_a", 3, 0), event("upper_a", 20, 1)])
+            .is_err());
+        assert!(first_outputs.try_recv().is_err());
+
+        composite.shutdown();
+    }
+}
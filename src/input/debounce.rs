@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use super::bitevents::BitEvent;
+
+/// Filters raw transitions for a single device, only letting a change through once the pin has
+/// held its new level for the configured settle time. A settle time of zero passes events through
+/// untouched.
+pub struct Debouncer {
+    dev_name: String,
+    default_settle: Duration,
+    pin_settle: BTreeMap<u8, Duration>,
+    // The last level reported for each pin
+    stable: u16,
+    // Pins that have changed but not yet settled, with the candidate level and when it was seen
+    pending: BTreeMap<u8, (u8, Instant)>,
+}
+
+impl Debouncer {
+    pub fn new(
+        dev_name: &str,
+        default_settle: Duration,
+        pin_settle: BTreeMap<u8, Duration>,
+    ) -> Debouncer {
+        Debouncer {
+            dev_name: String::from(dev_name),
+            default_settle,
+            pin_settle,
+            stable: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Forget any pending changes and treat the given pin state as stable
+    pub fn reset(&mut self, stable: u16) {
+        self.stable = stable;
+        self.pending.clear();
+    }
+
+    /// The last level reported for every pin
+    pub fn stable(&self) -> u16 {
+        self.stable
+    }
+
+    fn settle_time(&self, bit: u8) -> Duration {
+        *self.pin_settle.get(&bit).unwrap_or(&self.default_settle)
+    }
+
+    fn stable_value(&self, bit: u8) -> u8 {
+        (self.stable >> bit & 0x01) as u8
+    }
+
+    fn set_stable(&mut self, bit: u8, value: u8) {
+        if value == 0 {
+            self.stable &= !(1 << bit);
+        } else {
+            self.stable |= 1 << bit;
+        }
+    }
+
+    /// Feed in raw events seen at `now`, returning every event that is now stable
+    pub fn process(&mut self, raw: Vec<BitEvent>, now: Instant) -> Vec<BitEvent> {
+        let mut events = Vec::new();
+
+        for event in raw {
+            if self.settle_time(event.bit) == Duration::from_secs(0) {
+                self.set_stable(event.bit, event.value);
+                events.push(event);
+            } else if event.value == self.stable_value(event.bit) {
+                // Bounced back before settling, so nothing really happened
+                self.pending.remove(&event.bit);
+            } else {
+                self.pending.insert(event.bit, (event.value, now));
+            }
+        }
+
+        let settled: Vec<(u8, u8)> = self
+            .pending
+            .iter()
+            .filter(|(&bit, &(_, since))| now.duration_since(since) >= self.settle_time(bit))
+            .map(|(&bit, &(value, _))| (bit, value))
+            .collect();
+
+        for (bit, value) in settled {
+            debug!("{} bit {} settled at {}", self.dev_name, bit, value);
+            self.pending.remove(&bit);
+            self.set_stable(bit, value);
+            events.push(BitEvent {
+                dev_name: self.dev_name.clone(),
+                bit,
+                value,
+            });
+        }
+
+        events
+    }
+
+    /// When the next pending change will have settled, if there are any
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .iter()
+            .map(|(&bit, &(_, since))| since + self.settle_time(bit))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debouncer;
+    use crate::input::bitevents::BitEvent;
+    use maplit::btreemap;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    fn event(bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from("test"),
+            bit,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_no_settle_passes_through() {
+        let mut debouncer = Debouncer::new("test", Duration::from_secs(0), BTreeMap::new());
+        let now = Instant::now();
+
+        assert_eq!(
+            debouncer.process(vec![event(1, 1), event(1, 0)], now),
+            vec![event(1, 1), event(1, 0)]
+        );
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_settle_delays_event() {
+        let settle = Duration::from_millis(20);
+        let mut debouncer = Debouncer::new("test", settle, BTreeMap::new());
+        let start = Instant::now();
+
+        assert_eq!(debouncer.process(vec![event(3, 1)], start), vec![]);
+        assert_eq!(debouncer.next_deadline(), Some(start + settle));
+        assert_eq!(
+            debouncer.process(vec![], start + Duration::from_millis(10)),
+            vec![]
+        );
+        assert_eq!(debouncer.process(vec![], start + settle), vec![event(3, 1)]);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_chatter_is_suppressed() {
+        let settle = Duration::from_millis(20);
+        let mut debouncer = Debouncer::new("test", settle, BTreeMap::new());
+        let start = Instant::now();
+
+        // Bouncing back to the stable level cancels the change
+        debouncer.process(vec![event(0, 1)], start);
+        debouncer.process(vec![event(0, 0)], start + Duration::from_millis(5));
+        assert_eq!(debouncer.process(vec![], start + settle), vec![]);
+
+        // Each bounce restarts the settle time
+        debouncer.process(vec![event(0, 1)], start + Duration::from_millis(30));
+        debouncer.process(vec![event(0, 0)], start + Duration::from_millis(35));
+        debouncer.process(vec![event(0, 1)], start + Duration::from_millis(40));
+        assert_eq!(
+            debouncer.process(vec![], start + Duration::from_millis(55)),
+            vec![]
+        );
+        assert_eq!(
+            debouncer.process(vec![], start + Duration::from_millis(60)),
+            vec![event(0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_per_pin_settle() {
+        let mut debouncer = Debouncer::new(
+            "test",
+            Duration::from_millis(50),
+            btreemap! { 2 => Duration::from_secs(0) },
+        );
+        debouncer.reset(0b0100);
+        let start = Instant::now();
+
+        assert_eq!(
+            debouncer.process(vec![event(1, 1), event(2, 0)], start),
+            vec![event(2, 0)]
+        );
+        assert_eq!(
+            debouncer.process(vec![], start + Duration::from_millis(50)),
+            vec![event(1, 1)]
+        );
+    }
+}
@@ -0,0 +1,809 @@
+//! In-memory I/O expanders, ADCs and displays that speak the same I2C protocol as the real
+//! chips, so that the driver code can be exercised without hardware. The MCP23017 answers at
+//! either bank's addresses, according to IOCON.BANK.
+
+use i2cdev::core::I2CDevice;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::ads1x15::AdcModel;
+use super::mcp23017::registers::{Iocon, Port, Register};
+use crate::input::InputError;
+
+// Register addresses, with IOCON.BANK == 0. Registers are kept in this layout whatever the bank
+pub const IODIR: u8 = Register::IODIR.address(Port::A, false);
+pub const IPOL: u8 = Register::IPOL.address(Port::A, false);
+pub const GPINTEN: u8 = Register::GPINTEN.address(Port::A, false);
+pub const DEFVAL: u8 = Register::DEFVAL.address(Port::A, false);
+pub const INTCON: u8 = Register::INTCON.address(Port::A, false);
+pub const IOCON: u8 = Register::IOCON.address(Port::A, false);
+pub const GPPU: u8 = Register::GPPU.address(Port::A, false);
+pub const INTF: u8 = Register::INTF.address(Port::A, false);
+pub const INTCAP: u8 = Register::INTCAP.address(Port::A, false);
+pub const GPIO: u8 = Register::GPIO.address(Port::A, false);
+pub const OLAT: u8 = Register::OLAT.address(Port::A, false);
+
+const REGISTER_COUNT: usize = 0x16;
+
+// The last register of each port with IOCON.BANK == 1, where sequential access moves on to the
+// other port
+const LAST_A: u8 = Register::OLAT.address(Port::A, true);
+const LAST_B: u8 = Register::OLAT.address(Port::B, true);
+const FIRST_B: u8 = Register::IODIR.address(Port::B, true);
+
+#[derive(Debug)]
+pub struct EmulatorError(String);
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Emulator error: {}", self.0)
+    }
+}
+
+impl Error for EmulatorError {}
+
+impl From<EmulatorError> for InputError {
+    fn from(err: EmulatorError) -> InputError {
+        InputError::new(format!("I2C Error: {}", err))
+    }
+}
+
+struct ChipState {
+    registers: [u8; REGISTER_COUNT],
+    // The register pointer, which auto-increments on sequential access
+    pointer: u8,
+    // Levels driven onto the pins from outside the chip, and which pins are actually driven
+    pin_levels: u16,
+    driven: u16,
+    // Whether the chip answers on the bus at all
+    connected: bool,
+    // How many read transactions the chip has answered
+    reads: usize,
+    // The most an I2C block read returns, as with adapters that can't manage longer ones
+    block_limit: Option<usize>,
+}
+
+impl ChipState {
+    fn new() -> ChipState {
+        let mut registers = [0u8; REGISTER_COUNT];
+        // Power-on reset has every pin configured as an input
+        registers[IODIR as usize] = 0xff;
+        registers[IODIR as usize + 1] = 0xff;
+
+        ChipState {
+            registers,
+            pointer: 0,
+            pin_levels: 0,
+            driven: 0,
+            connected: true,
+            reads: 0,
+            block_limit: None,
+        }
+    }
+
+    fn word(&self, register: u8) -> u16 {
+        let index = register as usize;
+        u16::from(self.registers[index]) | u16::from(self.registers[index + 1]) << 8
+    }
+
+    /// The value the GPIO register reports: inputs (with IPOL applied) and the latch for outputs
+    fn gpio(&self) -> u16 {
+        let inputs = self.word(IODIR);
+        let floating = self.word(GPPU) & !self.driven;
+        let levels = (self.pin_levels & self.driven) | floating;
+
+        ((levels ^ self.word(IPOL)) & inputs) | (self.word(OLAT) & !inputs)
+    }
+
+    /// Read a register without the side effects of a bus read
+    fn peek(&self, register: u8) -> u8 {
+        match register {
+            r if r == GPIO => self.gpio() as u8,
+            r if r == GPIO + 1 => (self.gpio() >> 8) as u8,
+            r => self.registers[r as usize],
+        }
+    }
+
+    /// Where a bus address lands in `registers`, if anywhere
+    fn locate(&self, address: u8) -> Option<u8> {
+        if self.registers[IOCON as usize] & Iocon::BANK == 0 {
+            return Some(address).filter(|&address| (address as usize) < REGISTER_COUNT);
+        }
+
+        match address {
+            a if a <= LAST_A => Some(a * 2),
+            a if (FIRST_B..=LAST_B).contains(&a) => Some((a - FIRST_B) * 2 + 1),
+            _ => None,
+        }
+    }
+
+    fn read_register(&mut self, register: u8) -> u8 {
+        let value = self.peek(register);
+
+        // Reading GPIO or INTCAP clears the interrupt for that port
+        if register == GPIO || register == INTCAP {
+            self.clear_interrupt(0);
+        } else if register == GPIO + 1 || register == INTCAP + 1 {
+            self.clear_interrupt(1);
+        }
+
+        value
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) {
+        match register {
+            // Writes to GPIO land in the output latch
+            r if r == GPIO || r == GPIO + 1 => {
+                self.registers[(r - GPIO + OLAT) as usize] = value;
+            }
+            // IOCON is a single register visible at two addresses
+            r if r == IOCON || r == IOCON + 1 => {
+                self.registers[IOCON as usize] = value;
+                self.registers[IOCON as usize + 1] = value;
+            }
+            // INTF and INTCAP are read-only
+            r if (INTF..INTCAP + 2).contains(&r) => {}
+            r => self.registers[r as usize] = value,
+        }
+    }
+
+    /// Change the external pin state, raising interrupts for any enabled pins that trip
+    fn update_pins<F: FnOnce(&mut ChipState)>(&mut self, update: F) {
+        let previous = self.gpio();
+        update(self);
+        let current = self.gpio();
+
+        for port in 0..2 {
+            let shift = port * 8;
+            let changed = ((previous ^ current) >> shift) as u8;
+            self.trigger(port, changed);
+        }
+    }
+
+    fn trigger(&mut self, port: usize, changed: u8) {
+        let current = (self.gpio() >> (port * 8)) as u8;
+        let enabled = self.registers[GPINTEN as usize + port];
+        let compare = self.registers[INTCON as usize + port];
+        let mismatched = current ^ self.registers[DEFVAL as usize + port];
+
+        let flags = enabled & ((!compare & changed) | (compare & mismatched));
+
+        // INTCAP holds its value until the pending interrupt is cleared
+        if flags != 0 && self.registers[INTF as usize + port] == 0 {
+            self.registers[INTF as usize + port] = flags;
+            self.registers[INTCAP as usize + port] = current;
+        }
+    }
+
+    fn clear_interrupt(&mut self, port: usize) {
+        self.registers[INTF as usize + port] = 0;
+        // A DEFVAL comparison that still mismatches fires again right away
+        self.trigger(port, 0);
+    }
+
+    fn advance(&mut self) {
+        let iocon = self.registers[IOCON as usize];
+
+        self.pointer = match (iocon & Iocon::BANK != 0, iocon & Iocon::SEQOP != 0) {
+            // Sequential operation is off, so only toggle between the A and B registers, or stay
+            // put with separate banks
+            (false, true) => self.pointer ^ 0x01,
+            (true, true) => self.pointer,
+            (false, false) => (self.pointer + 1) % REGISTER_COUNT as u8,
+            (true, false) => match self.pointer {
+                LAST_A => FIRST_B,
+                LAST_B => 0,
+                pointer => pointer + 1,
+            },
+        };
+    }
+}
+
+/// A handle on an emulated chip. Clones share the same chip, so a test can keep one handle to
+/// drive pins while the driver owns another.
+#[derive(Clone)]
+pub struct EmulatedMCP23017 {
+    state: Arc<Mutex<ChipState>>,
+}
+
+impl EmulatedMCP23017 {
+    pub fn new() -> EmulatedMCP23017 {
+        EmulatedMCP23017 {
+            state: Arc::new(Mutex::new(ChipState::new())),
+        }
+    }
+
+    /// Externally drive the given pin high or low
+    pub fn set_pin(&self, bit: u8, high: bool) {
+        let mask = 1 << bit;
+
+        self.state.lock().unwrap().update_pins(|state| {
+            state.driven |= mask;
+            if high {
+                state.pin_levels |= mask;
+            } else {
+                state.pin_levels &= !mask;
+            }
+        });
+    }
+
+    /// Stop driving the given pin, leaving it to float (or to its pullup)
+    pub fn release_pin(&self, bit: u8) {
+        self.state
+            .lock()
+            .unwrap()
+            .update_pins(|state| state.driven &= !(1 << bit));
+    }
+
+    /// Read a 16-bit register pair (port A in the low byte) without any side effects. The
+    /// register is given by its BANK 0 address, whatever bank the chip is in
+    pub fn register(&self, register: u8) -> u16 {
+        let state = self.state.lock().unwrap();
+        u16::from(state.peek(register)) | u16::from(state.peek(register + 1)) << 8
+    }
+
+    /// Whether either port has an interrupt pending
+    pub fn interrupt_raised(&self) -> bool {
+        self.register(INTF) != 0
+    }
+
+    /// Stop answering on the bus, as if the cable had come loose
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().connected = false;
+    }
+
+    /// Come back with power-on register values, keeping whatever is driving the pins
+    pub fn reconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+        let (pin_levels, driven) = (state.pin_levels, state.driven);
+
+        *state = ChipState::new();
+        state.pin_levels = pin_levels;
+        state.driven = driven;
+    }
+
+    /// Cut I2C block reads short at the given length
+    pub fn limit_block_reads(&self, len: usize) {
+        self.state.lock().unwrap().block_limit = Some(len);
+    }
+
+    /// How many read transactions the chip has answered, however many bytes each was
+    pub fn reads(&self) -> usize {
+        self.state.lock().unwrap().reads
+    }
+
+    /// The levels present on the output pins
+    pub fn outputs(&self) -> u16 {
+        let state = self.state.lock().unwrap();
+        state.word(OLAT) & !state.word(IODIR)
+    }
+}
+
+impl I2CDevice for EmulatedMCP23017 {
+    type Error = EmulatorError;
+
+    fn read(&mut self, data: &mut [u8]) -> Result<(), EmulatorError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(EmulatorError(String::from("No ACK")));
+        }
+        state.reads += 1;
+        for byte in data.iter_mut() {
+            // Unimplemented addresses read as zero
+            *byte = match state.locate(state.pointer) {
+                Some(register) => state.read_register(register),
+                None => 0,
+            };
+            state.advance();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), EmulatorError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(EmulatorError(String::from("No ACK")));
+        }
+
+        match data.split_first() {
+            Some((&register, values)) if state.locate(register).is_some() => {
+                state.pointer = register;
+                for &value in values {
+                    if let Some(register) = state.locate(state.pointer) {
+                        state.write_register(register, value);
+                    }
+                    state.advance();
+                }
+                Ok(())
+            }
+            Some((&register, _)) => Err(EmulatorError(format!(
+                "Invalid register 0x{:02x}",
+                register
+            ))),
+            None => Err(EmulatorError(String::from("Empty write"))),
+        }
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    fn smbus_read_block_data(&mut self, register: u8) -> Result<Vec<u8>, EmulatorError> {
+        self.smbus_read_i2c_block_data(register, REGISTER_COUNT as u8)
+    }
+
+    fn smbus_read_i2c_block_data(
+        &mut self,
+        register: u8,
+        len: u8,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        self.smbus_write_byte(register)?;
+        let limit = self.state.lock().unwrap().block_limit;
+        let len = limit.map_or(len as usize, |limit| limit.min(len as usize));
+        let mut data = vec![0u8; len];
+        self.read(&mut data)?;
+        Ok(data)
+    }
+
+    fn smbus_write_block_data(&mut self, register: u8, values: &[u8]) -> Result<(), EmulatorError> {
+        let mut data = vec![register];
+        data.extend_from_slice(values);
+        self.write(&data)
+    }
+
+    fn smbus_process_block(&mut self, register: u8, values: &[u8]) -> Result<(), EmulatorError> {
+        self.smbus_write_block_data(register, values)
+    }
+}
+
+struct PcfState {
+    latch: u16,
+    // Levels driven onto the pins from outside the chip, and which pins are actually driven
+    pin_levels: u16,
+    driven: u16,
+}
+
+/// An in-memory PCF8574/PCF8575. A high latch is only a weak pullup, so anything driving a pin
+/// low wins, while a low latch always pulls the pin low.
+#[derive(Clone)]
+pub struct EmulatedPCF857x {
+    bytes: usize,
+    state: Arc<Mutex<PcfState>>,
+}
+
+impl EmulatedPCF857x {
+    pub fn new(pin_count: u8) -> EmulatedPCF857x {
+        EmulatedPCF857x {
+            bytes: usize::from(pin_count / 8),
+            // Power on with every pin high
+            state: Arc::new(Mutex::new(PcfState {
+                latch: 0xffff,
+                pin_levels: 0,
+                driven: 0,
+            })),
+        }
+    }
+
+    pub fn set_pin(&self, bit: u8, high: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.driven |= 1 << bit;
+        if high {
+            state.pin_levels |= 1 << bit;
+        } else {
+            state.pin_levels &= !(1 << bit);
+        }
+    }
+
+    pub fn latch(&self) -> u16 {
+        self.state.lock().unwrap().latch
+    }
+
+    fn levels(&self) -> u16 {
+        let state = self.state.lock().unwrap();
+        state.latch & (state.pin_levels | !state.driven)
+    }
+}
+
+impl I2CDevice for EmulatedPCF857x {
+    type Error = EmulatorError;
+
+    fn read(&mut self, data: &mut [u8]) -> Result<(), EmulatorError> {
+        let levels = self.levels();
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = (levels >> (8 * (index % self.bytes))) as u8;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), EmulatorError> {
+        // The last complete write wins
+        match data.chunks_exact(self.bytes).last() {
+            Some(chunk) => {
+                let latch = chunk.iter().enumerate().fold(0, |latch, (index, &byte)| {
+                    latch | u16::from(byte) << (8 * index)
+                });
+                self.state.lock().unwrap().latch = latch;
+                Ok(())
+            }
+            None => Err(EmulatorError(String::from("Short write"))),
+        }
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    fn smbus_read_block_data(&mut self, _register: u8) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError(String::from("No registers")))
+    }
+
+    fn smbus_read_i2c_block_data(
+        &mut self,
+        _register: u8,
+        _len: u8,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError(String::from("No registers")))
+    }
+
+    fn smbus_write_block_data(
+        &mut self,
+        _register: u8,
+        _values: &[u8],
+    ) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("No registers")))
+    }
+
+    fn smbus_process_block(&mut self, _register: u8, _values: &[u8]) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("No registers")))
+    }
+}
+
+struct DisplayState {
+    ram: [u8; 16],
+    on: bool,
+    // The dimming level, 0-15
+    brightness: u8,
+}
+
+/// An in-memory HT16K33. Single byte writes are commands, longer ones fill display RAM from the
+/// address in the first byte
+#[derive(Clone)]
+pub struct EmulatedHT16K33 {
+    state: Arc<Mutex<DisplayState>>,
+}
+
+impl EmulatedHT16K33 {
+    pub fn new() -> EmulatedHT16K33 {
+        EmulatedHT16K33 {
+            state: Arc::new(Mutex::new(DisplayState {
+                ram: [0; 16],
+                on: false,
+                brightness: 15,
+            })),
+        }
+    }
+
+    /// The low byte of every row, which is all a 7-segment display uses
+    pub fn rows(&self) -> [u8; 8] {
+        let ram = self.state.lock().unwrap().ram;
+        let mut rows = [0; 8];
+        for (row, value) in rows.iter_mut().enumerate() {
+            *value = ram[row * 2];
+        }
+        rows
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.state.lock().unwrap().on
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.state.lock().unwrap().brightness
+    }
+}
+
+impl I2CDevice for EmulatedHT16K33 {
+    type Error = EmulatorError;
+
+    fn read(&mut self, _data: &mut [u8]) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), EmulatorError> {
+        let mut state = self.state.lock().unwrap();
+
+        match data {
+            [] => return Err(EmulatorError(String::from("Empty write"))),
+            [command] => match command & 0xF0 {
+                // Oscillator on/off doesn't matter here
+                0x20 => {}
+                0x80 => state.on = command & 0x01 != 0,
+                0xE0 => state.brightness = command & 0x0F,
+                _ => return Err(EmulatorError(format!("Invalid command {:#x}", command))),
+            },
+            [address, values @ ..] => {
+                for (offset, &value) in values.iter().enumerate() {
+                    let index = (usize::from(*address) + offset) % 16;
+                    state.ram[index] = value;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    fn smbus_read_block_data(&mut self, _register: u8) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn smbus_read_i2c_block_data(
+        &mut self,
+        _register: u8,
+        _len: u8,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn smbus_write_block_data(
+        &mut self,
+        _register: u8,
+        _values: &[u8],
+    ) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn smbus_process_block(&mut self, _register: u8, _values: &[u8]) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+}
+
+struct AdcState {
+    pointer: u8,
+    config: u16,
+    conversion: u16,
+    // The voltage on AIN0-AIN3
+    volts: [f64; 4],
+}
+
+/// An in-memory ADS1115/ADS1015, taking single shot readings of whatever voltage each input was
+/// last set to. Conversions finish as soon as they are started
+#[derive(Clone)]
+pub struct EmulatedADS1x15 {
+    model: AdcModel,
+    state: Arc<Mutex<AdcState>>,
+}
+
+impl EmulatedADS1x15 {
+    pub fn new(model: AdcModel) -> EmulatedADS1x15 {
+        EmulatedADS1x15 {
+            model,
+            state: Arc::new(Mutex::new(AdcState {
+                pointer: 0,
+                // Single shot mode, with no conversion running
+                config: 0x8583,
+                conversion: 0,
+                volts: [0.0; 4],
+            })),
+        }
+    }
+
+    pub fn set_volts(&self, channel: usize, volts: f64) {
+        self.state.lock().unwrap().volts[channel] = volts;
+    }
+
+    fn convert(&self, config: u16, volts: &[f64; 4]) -> Result<u16, EmulatorError> {
+        // Only single ended readings, AIN0-AIN3 against ground
+        let mux = config >> 12 & 0x07;
+        if mux < 4 {
+            return Err(EmulatorError(String::from(
+                "Differential inputs aren't emulated",
+            )));
+        }
+
+        let range =
+            [6.144, 4.096, 2.048, 1.024, 0.512, 0.256][usize::from(config >> 9 & 0x07).min(5)];
+        let fraction = volts[usize::from(mux - 4)] / range;
+
+        Ok(match self.model {
+            AdcModel::Ads1115 => (fraction * 32768.0).clamp(-32768.0, 32767.0) as i16 as u16,
+            // 12 bits, left justified
+            AdcModel::Ads1015 => ((fraction * 2048.0).clamp(-2048.0, 2047.0) as i16 as u16) << 4,
+        })
+    }
+}
+
+impl I2CDevice for EmulatedADS1x15 {
+    type Error = EmulatorError;
+
+    // Registers are read most significant byte first
+    fn read(&mut self, data: &mut [u8]) -> Result<(), EmulatorError> {
+        let state = self.state.lock().unwrap();
+        let value = match state.pointer {
+            0x00 => state.conversion,
+            0x01 => state.config,
+            register => return Err(EmulatorError(format!("Invalid register {:#x}", register))),
+        };
+
+        for (byte, value) in data.iter_mut().zip(&value.to_be_bytes()) {
+            *byte = *value;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), EmulatorError> {
+        let mut state = self.state.lock().unwrap();
+
+        match *data {
+            [register] if register < 0x04 => state.pointer = register,
+            [0x01, high, low] => {
+                let config = u16::from_be_bytes([high, low]);
+                // Setting OS starts a conversion, which is already done when OS is read back
+                if config & 0x8000 != 0 {
+                    state.conversion = self.convert(config, &state.volts)?;
+                }
+                state.pointer = 0x01;
+                state.config = config | 0x8000;
+            }
+            // The thresholds aren't used
+            [register @ 0x02..=0x03, _, _] => state.pointer = register,
+            _ => return Err(EmulatorError(format!("Invalid write {:x?}", data))),
+        }
+
+        Ok(())
+    }
+
+    fn smbus_write_quick(&mut self, _bit: bool) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    fn smbus_read_block_data(&mut self, _register: u8) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn smbus_read_i2c_block_data(
+        &mut self,
+        _register: u8,
+        _len: u8,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn smbus_write_block_data(
+        &mut self,
+        _register: u8,
+        _values: &[u8],
+    ) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+
+    fn smbus_process_block(&mut self, _register: u8, _values: &[u8]) -> Result<(), EmulatorError> {
+        Err(EmulatorError(String::from("Not emulated")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_state() {
+        let mut chip = EmulatedMCP23017::new();
+
+        assert_eq!(chip.smbus_read_word_data(IODIR).unwrap(), 0xffff);
+        assert_eq!(chip.smbus_read_word_data(GPIO).unwrap(), 0x0000);
+    }
+
+    #[test]
+    fn test_pullup_and_polarity() {
+        let mut chip = EmulatedMCP23017::new();
+
+        chip.smbus_write_word_data(GPPU, 0x00ff).unwrap();
+        chip.set_pin(0, false);
+        chip.set_pin(9, true);
+
+        assert_eq!(chip.smbus_read_word_data(GPIO).unwrap(), 0x02fe);
+
+        chip.smbus_write_word_data(IPOL, 0xffff).unwrap();
+
+        assert_eq!(chip.smbus_read_word_data(GPIO).unwrap(), 0xfd01);
+    }
+
+    #[test]
+    fn test_outputs_use_latch() {
+        let mut chip = EmulatedMCP23017::new();
+
+        chip.smbus_write_word_data(IODIR, 0xff00).unwrap();
+        chip.smbus_write_word_data(IPOL, 0xffff).unwrap();
+        chip.smbus_write_word_data(GPIO, 0x0f0f).unwrap();
+
+        assert_eq!(chip.register(OLAT), 0x0f0f);
+        assert_eq!(chip.outputs(), 0x000f);
+        // Outputs ignore IPOL, floating inputs without pullups read low before inversion
+        assert_eq!(chip.smbus_read_word_data(GPIO).unwrap(), 0xff0f);
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut chip = EmulatedMCP23017::new();
+
+        assert!(chip.smbus_write_byte_data(0x40, 0x01).is_err());
+    }
+
+    #[test]
+    fn test_bank_1() {
+        let mut chip = EmulatedMCP23017::new();
+        let bank_1 = |register: Register, port| register.address(port, true);
+
+        chip.smbus_write_byte_data(IOCON, Iocon::BANK).unwrap();
+        // IOCON moves along with everything else
+        assert_eq!(
+            chip.smbus_read_byte_data(bank_1(Register::IOCON, Port::B))
+                .unwrap(),
+            Iocon::BANK
+        );
+
+        chip.smbus_write_byte_data(bank_1(Register::IODIR, Port::A), 0x00)
+            .unwrap();
+        chip.smbus_write_byte_data(bank_1(Register::OLAT, Port::A), 0x81)
+            .unwrap();
+        assert_eq!(chip.outputs(), 0x0081);
+
+        // Sequential reads run off the end of port A onto port B
+        let data = chip
+            .smbus_read_i2c_block_data(bank_1(Register::OLAT, Port::A), 2)
+            .unwrap();
+        assert_eq!(data, vec![0x81, 0xff]);
+
+        assert!(chip.smbus_write_byte_data(0x0b, 0x01).is_err());
+    }
+
+    #[test]
+    fn test_interrupt_on_change() {
+        let mut chip = EmulatedMCP23017::new();
+
+        chip.smbus_write_word_data(GPPU, 0xffff).unwrap();
+        chip.smbus_write_word_data(GPINTEN, 0x0101).unwrap();
+
+        // Pin 1 is not enabled for interrupts
+        chip.set_pin(1, false);
+        assert!(!chip.interrupt_raised());
+
+        // A short press is captured even though it has been released before the read
+        chip.set_pin(8, false);
+        chip.set_pin(8, true);
+        assert!(chip.interrupt_raised());
+        assert_eq!(chip.smbus_read_word_data(INTF).unwrap(), 0x0100);
+        // Port A never captured anything
+        assert_eq!(chip.register(INTCAP), 0xfe00);
+
+        assert_eq!(chip.smbus_read_word_data(INTCAP).unwrap(), 0xfe00);
+        assert!(!chip.interrupt_raised());
+        assert_eq!(chip.smbus_read_word_data(GPIO).unwrap(), 0xfffd);
+    }
+
+    #[test]
+    fn test_interrupt_on_defval() {
+        let mut chip = EmulatedMCP23017::new();
+
+        chip.smbus_write_word_data(GPINTEN, 0x0001).unwrap();
+        chip.smbus_write_word_data(INTCON, 0x0001).unwrap();
+        chip.smbus_write_word_data(DEFVAL, 0x0000).unwrap();
+
+        chip.set_pin(0, true);
+        assert!(chip.interrupt_raised());
+
+        // Still mismatched, so clearing raises it again
+        chip.smbus_read_word_data(GPIO).unwrap();
+        assert!(chip.interrupt_raised());
+
+        chip.set_pin(0, false);
+        chip.smbus_read_word_data(GPIO).unwrap();
+        assert!(!chip.interrupt_raised());
+    }
+}
@@ -0,0 +1,220 @@
+use evdev::{Device, InputEventKind, Key};
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use super::bitevents::BitEvent;
+use super::*;
+
+/// A Linux input device (keyboard, gamepad, arcade encoder) and how its keys map onto panel inputs
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct EventDeviceConfig {
+    pub dev_path: PathBuf,
+    // Take exclusive access, so that key presses don't also land on the console
+    #[serde(default)]
+    pub grab: bool,
+    // Key names (e.g. KEY_A, BTN_TRIGGER) or raw numeric codes
+    pub keys: BTreeMap<String, KeyTarget>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct KeyTarget {
+    pub dev_name: String,
+    pub bit: u8,
+}
+
+type KeyMap = BTreeMap<u16, KeyTarget>;
+
+const STOPPED: &str = "All input devices have stopped";
+
+// Values for EV_KEY events
+const KEY_RELEASED: i32 = 0;
+const KEY_PRESSED: i32 = 1;
+
+pub struct EventDeviceInput {
+    tx: Sender<Vec<BitEvent>>,
+    rx: Receiver<Vec<BitEvent>>,
+}
+
+impl EventDeviceInput {
+    /// Open every device listed in the given YAML key map file
+    pub fn new(config_file: &Path) -> Result<EventDeviceInput, InputError> {
+        let mut contents = String::new();
+        File::open(config_file)?.read_to_string(&mut contents)?;
+
+        let configs: Vec<EventDeviceConfig> = serde_yaml::from_str(&contents)
+            .map_err(|err| InputError::new(format!("Invalid key map: {}", err)))?;
+
+        let (tx, rx) = channel();
+
+        for config in configs {
+            let keys = parse_key_map(config.keys)?;
+            let mut device = Device::open(&config.dev_path)?;
+
+            info!(
+                "Reading {} from {:?}",
+                device.name().unwrap_or("unnamed device"),
+                config.dev_path
+            );
+
+            if config.grab {
+                device.grab()?;
+            }
+
+            let dev_path = config.dev_path;
+            let tx = tx.clone();
+            thread::spawn(move || read_device(dev_path, device, keys, tx));
+        }
+
+        Ok(EventDeviceInput { tx, rx })
+    }
+}
+
+fn parse_key_map(keys: BTreeMap<String, KeyTarget>) -> Result<KeyMap, InputError> {
+    keys.into_iter()
+        .map(|(name, target)| {
+            let code = match Key::from_str(&name) {
+                Ok(key) => key.code(),
+                Err(_) => name
+                    .parse()
+                    .map_err(|_| InputError::new(format!("Unknown key '{}'", name)))?,
+            };
+
+            Ok((code, target))
+        })
+        .collect()
+}
+
+/// Translate a key event into a panel input, ignoring unmapped keys and autorepeat
+fn map_key(keys: &KeyMap, code: u16, value: i32) -> Option<BitEvent> {
+    let target = keys.get(&code)?;
+
+    let bit_value = match value {
+        KEY_PRESSED => 1,
+        KEY_RELEASED => 0,
+        _ => return None,
+    };
+
+    Some(BitEvent {
+        dev_name: target.dev_name.clone(),
+        bit: target.bit,
+        value: bit_value,
+    })
+}
+
+fn read_device(dev_path: PathBuf, mut device: Device, keys: KeyMap, tx: Sender<Vec<BitEvent>>) {
+    loop {
+        let events: Vec<BitEvent> = match device.fetch_events() {
+            Ok(fetched) => fetched
+                .filter_map(|event| match event.kind() {
+                    InputEventKind::Key(key) => map_key(&keys, key.code(), event.value()),
+                    _ => None,
+                })
+                .collect(),
+            Err(err) => {
+                error!("Error reading {:?}: {}", dev_path, err);
+                return;
+            }
+        };
+
+        if !events.is_empty() && tx.send(events).is_err() {
+            // The handler is gone, nobody is listening any more
+            return;
+        }
+    }
+}
+
+impl InputHandler for EventDeviceInput {
+    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        receive_batches(&self.rx, None, STOPPED)
+    }
+
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        receive_batches(&self.rx, Some(timeout), STOPPED)
+    }
+
+    fn waker(&self) -> Waker {
+        batch_waker(&self.tx)
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        debug!("Input devices have no outputs, ignoring {:?}", bits);
+        Ok(())
+    }
+
+    fn shutdown(self) {
+        debug!("Shutdown is NOOP on input devices");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{map_key, parse_key_map, KeyTarget};
+    use crate::input::bitevents::BitEvent;
+    use maplit::btreemap;
+
+    fn target(dev_name: &str, bit: u8) -> KeyTarget {
+        KeyTarget {
+            dev_name: String::from(dev_name),
+            bit,
+        }
+    }
+
+    #[test]
+    fn test_parse_key_names_and_codes() {
+        let keys = parse_key_map(btreemap! {
+            String::from("KEY_A") => target("main_a", 0),
+            String::from("BTN_TRIGGER") => target("main_a", 1),
+            String::from("59") => target("main_b", 7),
+        })
+        .unwrap();
+
+        assert_eq!(
+            keys,
+            btreemap! {
+                30 => target("main_a", 0),
+                288 => target("main_a", 1),
+                59 => target("main_b", 7),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_key() {
+        assert!(
+            parse_key_map(btreemap! { String::from("KEY_NOPE") => target("main_a", 0) }).is_err()
+        );
+    }
+
+    #[test]
+    fn test_map_key() {
+        let keys = btreemap! { 30 => target("main_a", 3) };
+
+        assert_eq!(
+            map_key(&keys, 30, 1),
+            Some(BitEvent {
+                dev_name: String::from("main_a"),
+                bit: 3,
+                value: 1,
+            })
+        );
+        assert_eq!(
+            map_key(&keys, 30, 0),
+            Some(BitEvent {
+                dev_name: String::from("main_a"),
+                bit: 3,
+                value: 0,
+            })
+        );
+        // Autorepeat and unmapped keys are dropped
+        assert_eq!(map_key(&keys, 30, 2), None);
+        assert_eq!(map_key(&keys, 31, 1), None);
+    }
+}
@@ -0,0 +1,110 @@
+use i2cdev::core::I2CDevice;
+
+use std::time::{Duration, Instant};
+
+use super::config::{PanelDeviceConfig, ReadMode};
+use super::{open_expander, Expander};
+use crate::input::InputError;
+
+/// The average time to read every expander once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CycleTime {
+    // Polling the pins
+    pub poll: Duration,
+    // Servicing an interrupt from every device
+    pub interrupt: Duration,
+}
+
+/// Time reading all of the configured expanders in each read mode. A mode that some of the
+/// devices can't use reports why instead
+pub fn benchmark(
+    device_config: &[PanelDeviceConfig],
+    cycles: u32,
+) -> Vec<(ReadMode, Result<CycleTime, InputError>)> {
+    [ReadMode::Bytes, ReadMode::Word, ReadMode::Block]
+        .iter()
+        .map(|&read_mode| {
+            let devices = device_config
+                .iter()
+                .filter_map(|config| match config {
+                    PanelDeviceConfig::Expander(config) => Some(config),
+                    _ => None,
+                })
+                .map(|config| {
+                    let mut config = config.clone();
+                    config.read_mode = read_mode;
+                    open_expander(&config)
+                })
+                .collect::<Result<Vec<_>, _>>();
+
+            (
+                read_mode,
+                devices.and_then(|mut devices| time_cycles(&mut devices, cycles)),
+            )
+        })
+        .collect()
+}
+
+/// Read every device `cycles` times over, first polling and then as if each had interrupted
+pub fn time_cycles<D>(devices: &mut [Expander<D>], cycles: u32) -> Result<CycleTime, InputError>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    let cycles = cycles.max(1);
+
+    let start = Instant::now();
+    for _ in 0..cycles {
+        for dev in devices.iter_mut() {
+            dev.poll_input()?;
+        }
+    }
+    let poll = start.elapsed() / cycles;
+
+    let start = Instant::now();
+    for _ in 0..cycles {
+        for dev in devices.iter_mut() {
+            dev.read_interrupt()?;
+        }
+    }
+    let interrupt = start.elapsed() / cycles;
+
+    Ok(CycleTime { poll, interrupt })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::time_cycles;
+    use crate::input::emulator::EmulatedMCP23017;
+    use crate::input::mcp23017::config::DeviceConfig;
+    use crate::input::mcp23017::setup_expander;
+
+    #[test]
+    fn test_time_cycles() {
+        let config: DeviceConfig = serde_yaml::from_str(
+            "
+dev_path: /dev/null
+dev_name: bench
+address: 0x20
+polarity_mask: 0x0000
+direction_mask: 0xffff
+read_mode: block
+",
+        )
+        .unwrap();
+
+        let chips = [EmulatedMCP23017::new(), EmulatedMCP23017::new()];
+        let mut devices: Vec<_> = chips
+            .iter()
+            .map(|chip| setup_expander(chip.clone(), &config).unwrap())
+            .collect();
+        let reads: Vec<usize> = chips.iter().map(|chip| chip.reads()).collect();
+
+        time_cycles(&mut devices, 10).unwrap();
+
+        // One transaction for each poll and each interrupt, on every device
+        for (chip, before) in chips.iter().zip(reads) {
+            assert_eq!(chip.reads() - before, 20);
+        }
+    }
+}
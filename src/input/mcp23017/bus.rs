@@ -0,0 +1,242 @@
+use i2cdev::core::I2CDevice;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use super::health::Health;
+use super::{Expander, POLL_TIME};
+use crate::input::bitevents::BitEvent;
+use crate::input::selector::decode_selectors;
+use crate::input::InputError;
+
+pub type BusEvents = Result<Vec<BitEvent>, InputError>;
+
+// How long a poller with nothing to do waits before checking whether it should stop
+const IDLE_TIME: Duration = Duration::from_secs(1);
+
+/// All of the devices on one I2C bus, polled by their own thread so that a slow bus can't hold up
+/// any other
+pub struct Bus<D: I2CDevice> {
+    pub dev_path: PathBuf,
+    // Shared with the poller, which only holds the lock while talking to the devices
+    pub devices: Arc<Mutex<Vec<Expander<D>>>>,
+    poller: JoinHandle<()>,
+    poll_condition: Arc<AtomicBool>,
+}
+
+impl<D> Bus<D>
+where
+    D: I2CDevice + Send + 'static,
+    InputError: From<D::Error>,
+{
+    /// Start polling the given devices. `interrupts` carries the indices (into `devices`) of
+    /// devices that have raised an interrupt
+    pub fn start(
+        dev_path: PathBuf,
+        devices: Vec<Expander<D>>,
+        interrupts: Option<Receiver<usize>>,
+        tx: Sender<BusEvents>,
+    ) -> Bus<D> {
+        let devices = Arc::new(Mutex::new(devices));
+        let poll_condition = Arc::new(AtomicBool::new(true));
+
+        let poller = {
+            let dev_path = dev_path.clone();
+            let devices = devices.clone();
+            let should_run = poll_condition.clone();
+
+            thread::spawn(move || run_poller(&dev_path, &devices, interrupts, &should_run, &tx))
+        };
+
+        Bus {
+            dev_path,
+            devices,
+            poller,
+            poll_condition,
+        }
+    }
+}
+
+impl<D: I2CDevice> Bus<D> {
+    pub fn shutdown(self) {
+        debug!("Shutting down poller for {:?}", self.dev_path);
+
+        self.poll_condition.store(false, Ordering::Relaxed);
+        if self.poller.join().is_err() {
+            error!("Poller for {:?} panicked", self.dev_path);
+        }
+    }
+}
+
+fn run_poller<D>(
+    dev_path: &Path,
+    devices: &Mutex<Vec<Expander<D>>>,
+    mut interrupts: Option<Receiver<usize>>,
+    should_run: &AtomicBool,
+    tx: &Sender<BusEvents>,
+) where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    let mut raised: Vec<usize> = Vec::new();
+
+    while should_run.load(Ordering::Relaxed) {
+        let started = Instant::now();
+        let (events, timeout) = poll_bus(
+            &mut devices.lock().unwrap(),
+            interrupts.as_ref(),
+            &mut raised,
+        );
+
+        debug!("Polled {:?} in {:?}", dev_path, started.elapsed());
+
+        if !events.is_empty() && tx.send(Ok(events)).is_err() {
+            // The handler is gone, nobody is listening any more
+            return;
+        }
+        let timeout = timeout.unwrap_or(IDLE_TIME);
+
+        match wait_for_interrupt(interrupts.as_ref(), timeout) {
+            Ok(index) => raised.extend(index),
+            Err(_) => {
+                // Without interrupts to wait on, every device on the bus has to be polled
+                error!(
+                    "Interrupt watchers for {:?} have stopped, polling instead",
+                    dev_path
+                );
+                interrupts = None;
+                for dev in devices.lock().unwrap().iter_mut() {
+                    dev.interrupt_driven = false;
+                }
+            }
+        }
+    }
+}
+
+/// Perform one pass over the devices on a bus, returning the settled events along with how long
+/// the poller may wait before it needs to look again (None for indefinitely). A device that fails
+/// is left out of the pass and retried later, so it can't stop the rest of the bus from working
+fn poll_bus<D>(
+    devices: &mut [Expander<D>],
+    interrupts: Option<&Receiver<usize>>,
+    raised: &mut Vec<usize>,
+) -> (Vec<BitEvent>, Option<Duration>)
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    let now = Instant::now();
+    let mut raw: Vec<Vec<BitEvent>> = Vec::with_capacity(devices.len());
+
+    for dev in devices.iter_mut() {
+        if !dev.health.is_due(now) {
+            raw.push(Vec::new());
+            continue;
+        }
+
+        let result = if dev.health.state() != Health::Online {
+            // It may have lost its configuration along with power
+            dev.recover()
+        } else if dev.interrupt_driven {
+            Ok(Vec::new())
+        } else {
+            dev.poll_input()
+        };
+
+        raw.push(check_health(dev, result.map_err(InputError::from), now));
+    }
+
+    if let Some(rx) = interrupts {
+        raised.extend(rx.try_iter());
+    }
+    raised.sort();
+    raised.dedup();
+
+    for index in raised.drain(..) {
+        let dev = &mut devices[index];
+        // Failing devices are read in full when they recover
+        if dev.health.state() == Health::Online {
+            let result = dev.read_interrupt();
+            let mut inputs = check_health(dev, result, now);
+            raw[index].append(&mut inputs);
+        }
+    }
+
+    // Only report changes that have held long enough to not be switch bounce
+    let now = Instant::now();
+    let mut events: Vec<BitEvent> = Vec::new();
+
+    for (dev, inputs) in devices.iter_mut().zip(raw) {
+        let settled = dev.debouncer.process(inputs, now);
+        events.append(&mut decode_selectors(&mut dev.selectors, settled, now));
+    }
+
+    // Wake up for the next poll, the next debounce or selector deadline, or the next retry of a
+    // failing device, whichever comes first
+    let polling = devices.iter().any(|dev| !dev.interrupt_driven);
+    let deadline = devices
+        .iter()
+        .flat_map(|dev| {
+            let selectors = dev
+                .selectors
+                .iter()
+                .filter_map(|selector| selector.next_deadline());
+            dev.debouncer
+                .next_deadline()
+                .into_iter()
+                .chain(dev.health.retry_at())
+                .chain(selectors)
+        })
+        .min()
+        .map(|deadline| deadline.saturating_duration_since(now));
+
+    let timeout = match (polling, deadline) {
+        (true, Some(deadline)) => Some(deadline.min(POLL_TIME)),
+        (true, None) => Some(POLL_TIME),
+        (false, deadline) => deadline,
+    };
+
+    (events, timeout)
+}
+
+/// Update a device's health according to the result of talking to it
+fn check_health<D: I2CDevice>(
+    dev: &mut Expander<D>,
+    result: Result<Vec<BitEvent>, InputError>,
+    now: Instant,
+) -> Vec<BitEvent> {
+    match result {
+        Ok(events) => {
+            dev.health.succeeded(&dev.dev_name);
+            events
+        }
+        Err(err) => {
+            dev.health.failed(&dev.dev_name, &err, now);
+            Vec::new()
+        }
+    }
+}
+
+/// Wait for the given time, returning early if a device raises an interrupt. Fails straight away
+/// once nothing is left to raise one
+fn wait_for_interrupt(
+    interrupts: Option<&Receiver<usize>>,
+    timeout: Duration,
+) -> Result<Option<usize>, RecvTimeoutError> {
+    match interrupts {
+        Some(rx) => match rx.recv_timeout(timeout) {
+            Ok(index) => Ok(Some(index)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(err) => Err(err),
+        },
+        None => {
+            thread::sleep(timeout);
+            Ok(None)
+        }
+    }
+}
@@ -1,14 +1,202 @@
-use serde::Deserialize;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use serde_yaml::Value;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
-#[derive(Deserialize, Debug, PartialEq)]
+use super::registers::Iocon;
+use crate::display::ht16k33::DisplayConfig;
+use crate::input::ads1x15::AdcConfig;
+use crate::input::selector::SelectorConfig;
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct DeviceConfig {
+    #[serde(default)]
+    pub device_type: ExpanderType,
     pub dev_path: PathBuf,
     pub dev_name: String,
     pub address: u16,
     pub polarity_mask: u16,
     pub direction_mask: u16,
+    // Devices without an interrupt config are polled
+    #[serde(default)]
+    pub interrupt: Option<InterruptConfig>,
+    // How long an input must hold a new level before it is reported. Zero disables debouncing
+    #[serde(default)]
+    pub debounce_ms: u64,
+    // Per-pin overrides of debounce_ms
+    #[serde(default)]
+    pub pin_debounce_ms: BTreeMap<u8, u64>,
+    // Groups of pins that are reported as a single rotary selector position
+    #[serde(default)]
+    pub selectors: Vec<SelectorConfig>,
+    // Register values to set beyond what the rest of the config implies
+    #[serde(default)]
+    pub registers: RegisterConfig,
+    // How register pairs are read over the bus, a byte at a time unless word or block is set
+    #[serde(default)]
+    pub read_mode: ReadMode,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadMode {
+    // A transaction for each port
+    #[default]
+    Bytes,
+    // Both ports in one transaction
+    Word,
+    // Like word, and interrupts read INTF, INTCAP and GPIO in one transaction. Needs SEQOP clear
+    Block,
 }
 
+impl DeviceConfig {
+    /// The IOCON the device is set up with, where the interrupt config fills in anything the
+    /// register config leaves out
+    pub fn iocon(&self) -> Iocon {
+        let iocon = &self.registers.iocon;
+        let interrupt = self.interrupt.as_ref();
+
+        Iocon {
+            bank: iocon.bank.unwrap_or(false),
+            mirror: iocon
+                .mirror
+                .or_else(|| interrupt.map(|interrupt| interrupt.mirror))
+                .unwrap_or(false),
+            seqop: iocon.seqop.unwrap_or(false),
+            odr: iocon
+                .odr
+                .or_else(|| interrupt.map(|interrupt| interrupt.open_drain))
+                .unwrap_or(false),
+            intpol: iocon.intpol.unwrap_or(false),
+        }
+    }
+}
+
+/// MCP230xx registers to set explicitly. Port A is the low byte of each value, port B the high
+/// byte. Anything left out is worked out from the rest of the config
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct RegisterConfig {
+    #[serde(default)]
+    pub iocon: IoconConfig,
+    #[serde(default)]
+    pub gpinten: Option<u16>,
+    #[serde(default)]
+    pub defval: Option<u16>,
+    #[serde(default)]
+    pub intcon: Option<u16>,
+    // The initial level of the output pins
+    #[serde(default)]
+    pub olat: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct IoconConfig {
+    #[serde(default)]
+    pub bank: Option<bool>,
+    #[serde(default)]
+    pub mirror: Option<bool>,
+    #[serde(default)]
+    pub seqop: Option<bool>,
+    #[serde(default)]
+    pub odr: Option<bool>,
+    #[serde(default)]
+    pub intpol: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpanderType {
+    #[default]
+    Mcp23017,
+    Mcp23008,
+    // Quasi-bidirectional, with no registers at all
+    Pcf8574,
+    Pcf8575,
+}
+
+impl ExpanderType {
+    pub fn pin_count(self) -> u8 {
+        match self {
+            ExpanderType::Mcp23017 | ExpanderType::Pcf8575 => 16,
+            ExpanderType::Mcp23008 | ExpanderType::Pcf8574 => 8,
+        }
+    }
+
+    pub fn pin_mask(self) -> u16 {
+        ((1u32 << self.pin_count()) - 1) as u16
+    }
+
+    pub fn is_pcf857x(self) -> bool {
+        matches!(self, ExpanderType::Pcf8574 | ExpanderType::Pcf8575)
+    }
+}
+
+/// Interrupt settings for a device whose INTA (and optionally INTB) pin is wired to a host GPIO
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct InterruptConfig {
+    // GPIO character device, e.g. /dev/gpiochip0
+    pub chip: PathBuf,
+    // Line offset wired to INTA
+    pub line: u32,
+    // Line offset wired to INTB, only needed when not mirroring
+    #[serde(default)]
+    pub line_b: Option<u32>,
+    // GPINTEN, defaults to all input pins
+    #[serde(default)]
+    pub enable_mask: Option<u16>,
+    // INTCON, a set bit compares against DEFVAL instead of the previous value
+    #[serde(default)]
+    pub control_mask: u16,
+    // DEFVAL
+    #[serde(default)]
+    pub default_value: u16,
+    // IOCON.MIRROR, ties INTA and INTB together
+    #[serde(default = "default_mirror")]
+    pub mirror: bool,
+    // IOCON.ODR, required when several devices share one line
+    #[serde(default)]
+    pub open_drain: bool,
+}
+
+fn default_mirror() -> bool {
+    true
+}
+
+/// An entry in the device config file, told apart by its device_type. Expanders can leave it out
 #[derive(Debug, PartialEq)]
-pub struct DeviceConfigParseErr(String);
+pub enum PanelDeviceConfig {
+    Adc(AdcConfig),
+    Display(DisplayConfig),
+    Expander(DeviceConfig),
+}
+
+impl<'de> Deserialize<'de> for PanelDeviceConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PanelDeviceConfig, D::Error> {
+        // Serde's own tagging can't default the tag, and untagged only says that nothing matched
+        let entry = Value::deserialize(deserializer)?;
+        let device_type = match entry.get("device_type") {
+            Some(device_type) => device_type
+                .as_str()
+                .ok_or_else(|| D::Error::custom("device_type must be a string"))?,
+            None => "mcp23017",
+        };
+
+        let config = match device_type {
+            "mcp23017" | "mcp23008" | "pcf8574" | "pcf8575" => {
+                DeviceConfig::deserialize(entry).map(PanelDeviceConfig::Expander)
+            }
+            "ads1115" | "ads1015" => AdcConfig::deserialize(entry).map(PanelDeviceConfig::Adc),
+            "ht16k33" => DisplayConfig::deserialize(entry).map(PanelDeviceConfig::Display),
+            _ => {
+                return Err(D::Error::custom(format!(
+                    "Unknown device_type {}, expected one of mcp23017, mcp23008, pcf8574, \
+                     pcf8575, ads1115, ads1015 or ht16k33",
+                    device_type
+                )))
+            }
+        };
+
+        config.map_err(D::Error::custom)
+    }
+}
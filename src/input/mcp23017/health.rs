@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+use super::POLL_TIME;
+use crate::input::InputError;
+
+// Consecutive failures before a device is considered gone rather than glitching
+const FAILURES_BEFORE_OFFLINE: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    Online,
+    // Recent failures, still retried on every poll
+    Degraded,
+    // Retried with exponential backoff
+    Offline,
+}
+
+/// Tracks whether a device is answering, and when to try it again if it isn't
+pub struct DeviceHealth {
+    state: Health,
+    failures: u32,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl DeviceHealth {
+    pub fn new() -> DeviceHealth {
+        DeviceHealth {
+            state: Health::Online,
+            failures: 0,
+            backoff: INITIAL_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    pub fn state(&self) -> Health {
+        self.state
+    }
+
+    /// Whether the device should be talked to at all as of `now`
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.retry_at {
+            Some(retry_at) => now >= retry_at,
+            None => true,
+        }
+    }
+
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    pub fn succeeded(&mut self, dev_name: &str) {
+        if self.state != Health::Online {
+            info!("{} is back online", dev_name);
+        }
+
+        *self = DeviceHealth::new();
+    }
+
+    pub fn failed(&mut self, dev_name: &str, err: &InputError, now: Instant) {
+        self.failures += 1;
+
+        if self.failures < FAILURES_BEFORE_OFFLINE {
+            warn!(
+                "{} is degraded ({} failures): {}",
+                dev_name, self.failures, err.message
+            );
+            self.state = Health::Degraded;
+            self.retry_at = Some(now + POLL_TIME);
+        } else {
+            if self.state != Health::Offline {
+                error!("{} is offline: {}", dev_name, err.message);
+            } else {
+                debug!("{} is still offline: {}", dev_name, err.message);
+            }
+            self.state = Health::Offline;
+            self.retry_at = Some(now + self.backoff);
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceHealth, Health, INITIAL_BACKOFF, MAX_BACKOFF};
+    use crate::input::InputError;
+    use std::time::Instant;
+
+    #[test]
+    fn test_backoff() {
+        let mut health = DeviceHealth::new();
+        let err = InputError::from_str("No ACK");
+        let now = Instant::now();
+
+        health.failed("test", &err, now);
+        health.failed("test", &err, now);
+        assert_eq!(health.state(), Health::Degraded);
+
+        health.failed("test", &err, now);
+        assert_eq!(health.state(), Health::Offline);
+        assert_eq!(health.retry_at(), Some(now + INITIAL_BACKOFF));
+        assert!(!health.is_due(now));
+
+        health.failed("test", &err, now);
+        assert_eq!(health.retry_at(), Some(now + INITIAL_BACKOFF * 2));
+
+        for _ in 0..20 {
+            health.failed("test", &err, now);
+        }
+        assert_eq!(health.retry_at(), Some(now + MAX_BACKOFF));
+
+        health.succeeded("test");
+        assert_eq!(health.state(), Health::Online);
+        assert!(health.is_due(now));
+    }
+}
@@ -0,0 +1,176 @@
+use gpio_cdev::{Chip, EventRequestFlags, LineEventHandle, LineRequestFlags};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use std::collections::BTreeMap;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::config::DeviceConfig;
+use crate::input::InputError;
+
+const CONSUMER_LABEL: &str = "gemini-panel";
+
+// How long a watcher waits for an edge before checking whether it should stop
+const WATCH_TIME: Duration = Duration::from_millis(100);
+
+impl From<gpio_cdev::Error> for InputError {
+    fn from(err: gpio_cdev::Error) -> InputError {
+        InputError::new(format!("GPIO Error: {}", err))
+    }
+}
+
+/// The threads watching the interrupt lines of one bus
+pub struct Watchers {
+    threads: Vec<JoinHandle<()>>,
+    watch_condition: Arc<AtomicBool>,
+}
+
+impl Watchers {
+    pub fn shutdown(self) {
+        self.watch_condition.store(false, Ordering::Relaxed);
+        for thread in self.threads {
+            if thread.join().is_err() {
+                error!("Interrupt watcher panicked");
+            }
+        }
+    }
+}
+
+/// Start watching the interrupt lines of all interrupt-driven devices. Each active edge on a
+/// line sends the indices (into `devices`) of every device wired to that line. Returns None if no
+/// device is configured for interrupts.
+pub fn watch_interrupts(
+    devices: &[&DeviceConfig],
+) -> Result<Option<(Receiver<usize>, Watchers)>, InputError> {
+    let mut lines: BTreeMap<(PathBuf, u32), (Vec<usize>, bool)> = BTreeMap::new();
+
+    for (index, config) in devices.iter().enumerate() {
+        if let Some(ref interrupt) = config.interrupt {
+            let iocon = config.iocon();
+            // INT pins are active-low unless IOCON.INTPOL is set. Open drain outputs can only
+            // pull low, whatever INTPOL says
+            let active_high = iocon.intpol && !iocon.odr;
+
+            let offsets = if iocon.mirror {
+                vec![interrupt.line]
+            } else {
+                interrupt
+                    .line_b
+                    .into_iter()
+                    .chain(Some(interrupt.line))
+                    .collect()
+            };
+
+            for offset in offsets {
+                let (indices, line_active_high) = lines
+                    .entry((interrupt.chip.clone(), offset))
+                    .or_insert_with(|| (Vec::new(), active_high));
+
+                if *line_active_high != active_high {
+                    return Err(InputError::new(format!(
+                        "{} disagrees with the other devices on line {} about interrupt polarity",
+                        config.dev_name, offset
+                    )));
+                }
+                indices.push(index);
+            }
+        }
+    }
+
+    if lines.is_empty() {
+        return Ok(None);
+    }
+
+    let (tx, rx) = channel();
+    let mut watchers = Watchers {
+        threads: Vec::new(),
+        watch_condition: Arc::new(AtomicBool::new(true)),
+    };
+
+    for ((chip, offset), (indices, active_high)) in lines {
+        match watch_line(
+            chip,
+            offset,
+            indices,
+            active_high,
+            tx.clone(),
+            watchers.watch_condition.clone(),
+        ) {
+            Ok(thread) => watchers.threads.push(thread),
+            Err(err) => {
+                watchers.shutdown();
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(Some((rx, watchers)))
+}
+
+fn watch_line(
+    chip_path: PathBuf,
+    offset: u32,
+    indices: Vec<usize>,
+    active_high: bool,
+    tx: Sender<usize>,
+    should_run: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, InputError> {
+    let mut chip = Chip::new(&chip_path)?;
+
+    let edge = if active_high {
+        EventRequestFlags::RISING_EDGE
+    } else {
+        EventRequestFlags::FALLING_EDGE
+    };
+    let mut events =
+        chip.get_line(offset)?
+            .events(LineRequestFlags::INPUT, edge, CONSUMER_LABEL)?;
+
+    debug!(
+        "Watching {:?} line {} for devices {:?}",
+        chip_path, offset, indices
+    );
+
+    Ok(thread::spawn(move || {
+        while should_run.load(Ordering::Relaxed) {
+            let event = match wait_for_edge(&events) {
+                Ok(true) => events.get_event().map_err(InputError::from),
+                Ok(false) => continue,
+                Err(err) => Err(err),
+            };
+
+            match event {
+                Ok(_) => {
+                    for index in &indices {
+                        if tx.send(*index).is_err() {
+                            // The handler is gone, nobody is listening any more
+                            return;
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Error watching {:?} line {}: {}", chip_path, offset, err);
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+/// Wait up to WATCH_TIME for an edge to be ready to read, so the watcher can be stopped
+fn wait_for_edge(events: &LineEventHandle) -> Result<bool, InputError> {
+    let mut fds = [PollFd::new(events.as_raw_fd(), PollFlags::POLLIN)];
+
+    match poll(&mut fds, WATCH_TIME.as_millis() as i32) {
+        Ok(ready) => Ok(ready > 0),
+        Err(Errno::EINTR) => Ok(false),
+        Err(err) => Err(InputError::new(format!("GPIO Error: {}", err))),
+    }
+}
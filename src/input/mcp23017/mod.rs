@@ -1,257 +1,1556 @@
 use i2cdev::core::*;
 use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
 
-use std::thread;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::time::Duration;
 
 use super::bitevents::*;
+use super::debounce::Debouncer;
+use super::selector::Selector;
 use super::*;
+use crate::display::ht16k33::{setup_ht16k33, DisplayConfig, HT16K33};
+use crate::input::ads1x15::{setup_ads1x15, ADS1x15, AdcConfig, AdcPoller};
 
+pub mod benchmark;
+mod bus;
 pub mod config;
-use crate::input::mcp23017::config::DeviceConfig;
+mod health;
+pub mod interrupt;
+mod pcf857x;
+pub mod registers;
+use self::bus::{Bus, BusEvents};
+use self::health::{DeviceHealth, Health};
+use self::interrupt::Watchers;
+use self::registers::{Iocon, Port, Register};
+use crate::input::mcp23017::config::{DeviceConfig, ExpanderType, PanelDeviceConfig, ReadMode};
 
 const POLL_TIME: Duration = Duration::from_millis(100);
 
-pub struct PanelInputHandler {
-    // Input is handled as a pair of device and previous state
-    devices: Vec<MCP23017>,
+pub struct PanelInputHandler<D: I2CDevice = LinuxI2CDevice> {
+    buses: Vec<Bus<D>>,
+    // Where each expander lives, as (bus index, device index on that bus), in config order
+    locations: Vec<(usize, usize)>,
+    adcs: Vec<AdcPoller<D>>,
+    // Output only, so they're written from the caller's thread with nothing to poll
+    displays: Vec<HT16K33<D>>,
+    // Stopped once the buses they wake are
+    watchers: Vec<Watchers>,
+    // Kept for waking up reads
+    tx: Sender<BusEvents>,
+    rx: Receiver<BusEvents>,
 }
 
-impl PanelInputHandler {
-    pub fn new(device_config: &[DeviceConfig]) -> Result<PanelInputHandler, InputError> {
-        let devices = setup_devices(device_config)?;
+impl PanelInputHandler<LinuxI2CDevice> {
+    pub fn new(device_config: &[PanelDeviceConfig]) -> Result<PanelInputHandler, InputError> {
+        let mut expander_config = Vec::new();
+        let mut adc_config = Vec::new();
+        let mut display_config = Vec::new();
 
-        let state = PanelInputHandler { devices };
+        for config in device_config {
+            match config {
+                PanelDeviceConfig::Expander(config) => expander_config.push(config),
+                PanelDeviceConfig::Adc(config) => adc_config.push(config),
+                PanelDeviceConfig::Display(config) => display_config.push(config),
+            }
+        }
+
+        let devices = setup_devices(&expander_config)?;
+        let adcs = adc_config
+            .into_iter()
+            .map(open_ads1x15)
+            .collect::<Result<_, _>>()?;
+        let displays = display_config
+            .into_iter()
+            .map(open_ht16k33)
+            .collect::<Result<_, _>>()?;
+        let mut interrupts = BTreeMap::new();
+        let mut watchers = Vec::new();
+
+        for (dev_path, configs) in group_by_bus(&expander_config) {
+            if let Some((rx, bus_watchers)) = interrupt::watch_interrupts(&configs)? {
+                interrupts.insert(dev_path, rx);
+                watchers.push(bus_watchers);
+            }
+        }
+
+        Ok(PanelInputHandler::start(
+            devices, adcs, displays, interrupts, watchers,
+        ))
+    }
+}
+
+impl<D> PanelInputHandler<D>
+where
+    D: I2CDevice + Send + 'static,
+    InputError: From<D::Error>,
+{
+    /// Build a handler around devices that have already been set up
+    #[cfg(test)]
+    pub fn from_devices(devices: Vec<Expander<D>>) -> PanelInputHandler<D> {
+        PanelInputHandler::start(devices, vec![], vec![], BTreeMap::new(), vec![])
+    }
+
+    /// Start a poller for each bus and each ADC. Interrupt channels carry device indices local to
+    /// their bus
+    fn start(
+        devices: Vec<Expander<D>>,
+        adcs: Vec<ADS1x15<D>>,
+        displays: Vec<HT16K33<D>>,
+        mut interrupts: BTreeMap<PathBuf, Receiver<usize>>,
+        watchers: Vec<Watchers>,
+    ) -> PanelInputHandler<D> {
+        let (tx, rx) = channel();
+
+        let mut grouped: Vec<(PathBuf, Vec<Expander<D>>)> = Vec::new();
+        let mut locations = Vec::with_capacity(devices.len());
+
+        for dev in devices {
+            let bus_index = match grouped.iter().position(|(path, _)| *path == dev.dev_path) {
+                Some(index) => index,
+                None => {
+                    grouped.push((dev.dev_path.clone(), Vec::new()));
+                    grouped.len() - 1
+                }
+            };
+
+            let bus_devices = &mut grouped[bus_index].1;
+            locations.push((bus_index, bus_devices.len()));
+            bus_devices.push(dev);
+        }
+
+        let buses = grouped
+            .into_iter()
+            .map(|(dev_path, devices)| {
+                let bus_interrupts = interrupts.remove(&dev_path);
+                Bus::start(dev_path, devices, bus_interrupts, tx.clone())
+            })
+            .collect();
+
+        let adcs = adcs
+            .into_iter()
+            .map(|adc| AdcPoller::start(adc, tx.clone()))
+            .collect();
+
+        PanelInputHandler {
+            buses,
+            locations,
+            adcs,
+            displays,
+            watchers,
+            tx,
+            rx,
+        }
+    }
+}
+
+/// Group device configs by bus, keeping the original order within each bus
+fn group_by_bus<'a>(device_config: &[&'a DeviceConfig]) -> Vec<(PathBuf, Vec<&'a DeviceConfig>)> {
+    let mut buses: Vec<(PathBuf, Vec<&DeviceConfig>)> = Vec::new();
+
+    for &config in device_config {
+        match buses.iter_mut().find(|(path, _)| *path == config.dev_path) {
+            Some((_, configs)) => configs.push(config),
+            None => buses.push((config.dev_path.clone(), vec![config])),
+        }
+    }
+
+    buses
+}
+
+fn compute_new_values(current_value: u16, levels: &[(u8, Level)]) -> u16 {
+    levels
+        .iter()
+        .fold(current_value, |value, &(pin, level)| match level {
+            Level::High => value | 1 << pin,
+            Level::Low => value & !(1 << pin),
+        })
+}
+
+// Outputs for each expander location and each display
+type OutputBatches = (Vec<(usize, Vec<BitEvent>)>, Vec<(usize, Vec<BitEvent>)>);
+
+impl<D> PanelInputHandler<D>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    /// Split a batch of outputs up by device (as indices into locations) and display, checking all
+    /// of it before anything is written
+    fn split_outputs(&self, bits: &[BitEvent]) -> Result<OutputBatches, InputError> {
+        let mut batches: Vec<(usize, Vec<BitEvent>)> = Vec::new();
+        let mut display_batches: Vec<(usize, Vec<BitEvent>)> = Vec::new();
+
+        for event in bits {
+            if let Some(display) = self
+                .displays
+                .iter()
+                .position(|display| display.dev_name == event.dev_name)
+            {
+                match display_batches
+                    .iter_mut()
+                    .find(|(existing, _)| *existing == display)
+                {
+                    Some((_, batch)) => batch.push(event.clone()),
+                    None => display_batches.push((display, vec![event.clone()])),
+                }
+                continue;
+            }
+
+            let location = self
+                .locations
+                .iter()
+                .position(|&(bus_index, index)| {
+                    self.buses[bus_index].devices.lock().unwrap()[index].dev_name == event.dev_name
+                })
+                .ok_or_else(|| {
+                    InputError::new(format!("Unknown output device {}", event.dev_name))
+                })?;
+
+            match batches
+                .iter_mut()
+                .find(|(existing, _)| *existing == location)
+            {
+                Some((_, batch)) => batch.push(event.clone()),
+                None => batches.push((location, vec![event.clone()])),
+            }
+        }
+
+        for (location, batch) in &batches {
+            let (bus_index, index) = self.locations[*location];
+            self.buses[bus_index].devices.lock().unwrap()[index].check_outputs(batch)?;
+        }
+
+        for (display, batch) in &display_batches {
+            self.displays[*display].check_outputs(batch)?;
+        }
+
+        Ok((batches, display_batches))
+    }
+}
+
+impl<D> InputHandler for PanelInputHandler<D>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        match self.rx.recv() {
+            Ok(events) => events,
+            Err(_) => Err(InputError::from_str("All bus pollers have stopped")),
+        }
+    }
+
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(events) => events,
+            Err(RecvTimeoutError::Timeout) => Ok(vec![]),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(InputError::from_str("All bus pollers have stopped"))
+            }
+        }
+    }
+
+    fn waker(&self) -> Waker {
+        let tx = self.tx.clone();
+        Waker::new(move || {
+            let _ = tx.send(Ok(Vec::new()));
+        })
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        let (batches, display_batches) = self.split_outputs(bits)?;
+
+        for (location, batch) in batches {
+            let (bus_index, index) = self.locations[location];
+            let mut devices = self.buses[bus_index].devices.lock().unwrap();
+            devices[index].set_outputs(&batch)?;
+        }
+
+        for (display, batch) in display_batches {
+            self.displays[display].set_outputs(&batch)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        self.split_outputs(bits).map(|_| ())
+    }
+
+    fn output_devices(&self) -> Vec<String> {
+        self.locations
+            .iter()
+            .map(|&(bus_index, index)| {
+                self.buses[bus_index].devices.lock().unwrap()[index]
+                    .dev_name
+                    .clone()
+            })
+            .chain(self.displays.iter().map(|display| display.dev_name.clone()))
+            .collect()
+    }
+
+    fn current_state(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        let mut events = Vec::new();
+
+        for bus in &self.buses {
+            for dev in bus.devices.lock().unwrap().iter() {
+                // Nobody knows what a device that isn't answering looks like
+                if dev.health.state() == Health::Online {
+                    events.append(&mut dev.current_state());
+                } else {
+                    warn!("No initial state for {}, it is not online", dev.dev_name);
+                }
+            }
+        }
+
+        for adc in &self.adcs {
+            events.append(&mut adc.adc.lock().unwrap().current_state());
+        }
+
+        Ok(events)
+    }
+
+    fn shutdown(self) {
+        for bus in self.buses {
+            bus.shutdown();
+        }
+
+        for adc in self.adcs {
+            adc.shutdown();
+        }
+
+        for watchers in self.watchers {
+            watchers.shutdown();
+        }
+    }
+}
+
+impl From<LinuxI2CError> for InputError {
+    fn from(err: LinuxI2CError) -> InputError {
+        InputError::new(format!("I2C Error: {}", err))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Low = 0,
+    High = 1,
+}
 
-        Ok(state)
+impl From<u8> for Level {
+    fn from(value: u8) -> Level {
+        if value == 0 {
+            Level::Low
+        } else {
+            Level::High
+        }
     }
 }
 
-fn compute_new_values(current_value: u16, bits: &[BitEvent]) -> u16 {
-    bits.iter().fold(current_value, |value, event| {
-        let raw_mask: u16 = 1 << event.bit;
+/// One I/O expander, whichever type it is. MCP230xx chips are driven through their registers,
+/// PCF857x chips (which have none) are read and written directly
+pub struct Expander<D: I2CDevice = LinuxI2CDevice> {
+    dev_name: String,
+    dev_path: PathBuf,
+    dev: D,
+    address: u16,
+    device_type: ExpanderType,
+    direction_mask: u16,
+    polarity_mask: u16,
+    previous_value: u16,
+    // What the output pins should be driving. The chip loses its latch on reset, so this is the
+    // copy that gets written back
+    output_latch: u16,
+    // Register settings beyond direction and polarity, written whenever the device is set up
+    iocon: Iocon,
+    gpinten: u16,
+    defval: u16,
+    intcon: u16,
+    interrupt_driven: bool,
+    read_mode: ReadMode,
+    debouncer: Debouncer,
+    selectors: Vec<Selector>,
+    health: DeviceHealth,
+}
+
+impl<D: I2CDevice> Expander<D> {
+    pub fn poll_input(&mut self) -> Result<Vec<BitEvent>, D::Error> {
+        let new_value = self.read_pins()?;
+
+        debug!("Current for {} : {:#x}", self.dev_name, new_value);
+        debug!("Prev for {}    : {:#x}", self.dev_name, self.previous_value);
+
+        let events = bit_compare(&self.dev_name, self.previous_value, new_value);
+        self.previous_value = new_value;
+        Ok(events)
+    }
+
+    /// Handle an interrupt from this device. INTCAP holds the port state at the time of the
+    /// interrupt, so a press that has already been released still produces both events.
+    pub fn read_interrupt(&mut self) -> Result<Vec<BitEvent>, InputError>
+    where
+        InputError: From<D::Error>,
+    {
+        if self.device_type.is_pcf857x() {
+            // Nothing is captured, reading the pins is all there is (and clears the interrupt)
+            return Ok(self.poll_input()?);
+        }
 
-        let mask = if event.value == 0 {
-            !raw_mask
+        let [flags, intcap, current] = if self.read_mode == ReadMode::Block {
+            // INTF, INTCAP and GPIO are next to each other. Even with no flags set the pins
+            // have been read (clearing anything raised since), so compare them anyway
+            self.read_register_block(Register::INTF)?
         } else {
-            raw_mask
+            let flags = self.read_register_pair(Register::INTF)?;
+
+            if flags == 0 {
+                // Another device on a shared line raised it
+                return Ok(Vec::new());
+            }
+
+            let intcap = self.read_register_pair(Register::INTCAP)?;
+            [flags, intcap, self.read_pins()?]
+        };
+
+        // INTCAP is only meaningful for the port(s) that actually raised the interrupt
+        let flagged_ports = port_mask(flags);
+        let captured = (intcap & flagged_ports) | (current & !flagged_ports);
+
+        debug!(
+            "Interrupt for {}: flags {:#x}, captured {:#x}",
+            self.dev_name, flags, captured
+        );
+
+        let mut events = bit_compare(&self.dev_name, self.previous_value, captured);
+        events.append(&mut bit_compare(&self.dev_name, captured, current));
+        self.previous_value = current;
+        Ok(events)
+    }
+
+    pub fn read_pins(&mut self) -> Result<u16, D::Error> {
+        let result = if self.device_type.is_pcf857x() {
+            let raw = pcf857x::read_port(&mut self.dev, self.device_type)?;
+            // No IPOL register, so match its behaviour here
+            raw ^ (!self.polarity_mask & self.direction_mask)
+        } else {
+            self.read_register_pair(Register::GPIO)?
+        };
+        debug!("Read 0x{:04x} from 0x{:02x}", result, self.address);
+        Ok(result)
+    }
+
+    /// Read both ports of a register, or the single port of an MCP23008
+    fn read_register_pair(&mut self, register: Register) -> Result<u16, D::Error> {
+        if self.device_type == ExpanderType::Mcp23008 {
+            return Ok(u16::from(self.dev.smbus_read_byte_data(register.index())?));
+        }
+
+        if self.read_mode != ReadMode::Bytes {
+            // Sequential addressing from A to B, in one transaction
+            return self
+                .dev
+                .smbus_read_word_data(register.address(Port::A, false));
+        }
+
+        let bank = self.iocon.bank;
+        let mut result = u16::from(
+            self.dev
+                .smbus_read_byte_data(register.address(Port::A, bank))?,
+        );
+        result |= u16::from(
+            self.dev
+                .smbus_read_byte_data(register.address(Port::B, bank))?,
+        ) << 8;
+        Ok(result)
+    }
+
+    /// Read `N` consecutive registers (both ports of each) in one transaction. Needs BANK and
+    /// SEQOP clear, so the address pointer runs through them
+    fn read_register_block<const N: usize>(
+        &mut self,
+        first: Register,
+    ) -> Result<[u16; N], InputError>
+    where
+        InputError: From<D::Error>,
+    {
+        let (start, width) = if self.device_type == ExpanderType::Mcp23008 {
+            (first.index(), 1)
+        } else {
+            (first.address(Port::A, false), 2)
+        };
+
+        let expected = N * width;
+        let data = self.dev.smbus_read_i2c_block_data(start, expected as u8)?;
+
+        // The adapter may not manage the whole block, and guessing at the rest would report
+        // inputs that never changed
+        if data.len() < expected {
+            return Err(InputError::new(format!(
+                "Short read from {}: {} of {} bytes",
+                self.dev_name,
+                data.len(),
+                expected
+            )));
+        }
+
+        let mut values = [0; N];
+        for (register, value) in values.iter_mut().enumerate() {
+            *value = (0..width).fold(0, |value, port| {
+                value | u16::from(data[register * width + port]) << (8 * port)
+            });
+        }
+        Ok(values)
+    }
+
+    fn write_register_pair(&mut self, register: Register, value: u16) -> Result<(), D::Error> {
+        if self.device_type == ExpanderType::Mcp23008 {
+            self.dev
+                .smbus_write_byte_data(register.index(), value as u8)
+        } else if self.iocon.bank {
+            self.dev
+                .smbus_write_byte_data(register.address(Port::A, true), value as u8)?;
+            self.dev
+                .smbus_write_byte_data(register.address(Port::B, true), (value >> 8) as u8)
+        } else {
+            // The address pointer toggles between the A and B registers even with SEQOP set
+            self.dev
+                .smbus_write_word_data(register.address(Port::A, false), value)
+        }
+    }
+
+    /// Write IOCON, wherever it is. The device may have been left in either bank, so it's written
+    /// at both addresses, in the order that lands the second write on IOCON either way. Whatever
+    /// the other write hits gets set up again afterwards, or straight away for the output latch
+    fn write_iocon(&mut self) -> Result<(), D::Error> {
+        let bits = self.iocon.bits();
+
+        if self.device_type == ExpanderType::Mcp23008 {
+            // No banks, and BANK must stay clear
+            return self
+                .dev
+                .smbus_write_byte_data(Register::IOCON.index(), bits & !Iocon::BANK);
+        }
+
+        let (first, second) = if self.iocon.bank {
+            (false, true)
+        } else {
+            (true, false)
         };
 
-        if event.value != 0 {
-            value | mask
+        self.dev
+            .smbus_write_byte_data(Register::IOCON.address(Port::A, first), bits)?;
+        self.dev
+            .smbus_write_byte_data(Register::IOCON.address(Port::A, second), bits)?;
+
+        if self.iocon.bank {
+            // A device that was in BANK 1 already took the first write as OLATA, which would
+            // glitch the outputs until the rest of the setup was done
+            self.write_latch()?;
+        }
+        Ok(())
+    }
+
+    /// Write the shadow latch out to the device. Reading the pins back to build on isn't safe,
+    /// since input pins (and their polarity) would get mixed into the outputs
+    fn write_latch(&mut self) -> Result<(), D::Error> {
+        let latch = self.output_latch & !self.direction_mask;
+
+        if self.device_type.is_pcf857x() {
+            // Input pins have to be left high so that they can be pulled down
+            pcf857x::write_port(&mut self.dev, self.device_type, latch | self.direction_mask)
         } else {
-            value & mask
+            self.write_register_pair(Register::OLAT, latch)
+        }
+    }
+
+    /// Set output pins from a batch of events, in a single write
+    pub fn set_outputs(&mut self, bits: &[BitEvent]) -> Result<(), D::Error> {
+        let levels: Vec<(u8, Level)> = bits
+            .iter()
+            .map(|event| (event.bit, Level::from(event.value)))
+            .collect();
+        self.set_pins(&levels)
+    }
+
+    /// Drive several output pins in a single write
+    pub fn set_pins(&mut self, levels: &[(u8, Level)]) -> Result<(), D::Error> {
+        let previous = self.output_latch;
+        self.output_latch = compute_new_values(previous, levels);
+
+        let result = self.write_latch();
+        if result.is_err() {
+            // The device still has the old values
+            self.output_latch = previous;
+        }
+        result
+    }
+
+    /// Write the device's configuration, as at startup or after it has dropped off the bus. The
+    /// outputs are put back to what they were before any reset
+    fn configure(&mut self) -> Result<(), D::Error> {
+        if !self.device_type.is_pcf857x() {
+            self.setup_registers()?;
         }
-    })
+        self.write_latch()
+    }
+
+    /// Make sure a batch of output events only sets real output pins on a device that's there
+    fn check_outputs(&self, bits: &[BitEvent]) -> Result<(), InputError> {
+        if self.health.state() == Health::Offline {
+            return Err(InputError::new(format!("{} is offline", self.dev_name)));
+        }
+
+        let pin_count = self.device_type.pin_count();
+
+        for event in bits {
+            if event.bit >= pin_count {
+                return Err(InputError::new(format!(
+                    "Invalid pin {} for {} with {} pins",
+                    event.bit, self.dev_name, pin_count
+                )));
+            }
+
+            if self.direction_mask >> event.bit & 0x01 != 0 {
+                return Err(InputError::new(format!(
+                    "Pin {} on {} is configured as an input",
+                    event.bit, self.dev_name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The debounced level of every input pin, with selectors reported as their position
+    pub fn current_state(&self) -> Vec<BitEvent> {
+        let stable = self.debouncer.stable();
+
+        let pins = (0..self.device_type.pin_count())
+            .filter(|&bit| self.direction_mask >> bit & 0x01 != 0)
+            .filter(|&bit| !self.selectors.iter().any(|selector| selector.owns(bit)))
+            .map(|bit| BitEvent {
+                dev_name: self.dev_name.clone(),
+                bit,
+                value: (stable >> bit & 0x01) as u8,
+            });
+
+        let selectors = self
+            .selectors
+            .iter()
+            .filter_map(|selector| selector.current_state());
+
+        pins.chain(selectors).collect()
+    }
+
+    /// Bring a device that has been failing back into service, reporting anything that changed
+    /// while it was away
+    pub fn recover(&mut self) -> Result<Vec<BitEvent>, D::Error> {
+        self.configure()?;
+        self.poll_input()
+    }
+
+    fn setup_registers(&mut self) -> Result<(), D::Error> {
+        // IOCON first, since it decides where everything else is
+        self.write_iocon()?;
+
+        // Set the IO Direction registers. Also enable the pullup on any input pins
+        self.write_register_pair(Register::IODIR, self.direction_mask)?;
+        self.write_register_pair(Register::GPPU, self.direction_mask)?;
+
+        // Set the polarity mask
+        self.write_register_pair(Register::IPOL, !self.polarity_mask)?;
+
+        self.write_register_pair(Register::DEFVAL, self.defval)?;
+        self.write_register_pair(Register::INTCON, self.intcon)?;
+        self.write_register_pair(Register::GPINTEN, self.gpinten)
+    }
+}
+
+/// Expand a register pair value into a mask of the ports that have any bit set
+fn port_mask(value: u16) -> u16 {
+    let mut mask = 0;
+    if value & 0x00ff != 0 {
+        mask |= 0x00ff;
+    }
+    if value & 0xff00 != 0 {
+        mask |= 0xff00;
+    }
+    mask
+}
+
+/// Check that every pin the config refers to exists on the device
+fn validate_pins(config: &DeviceConfig) -> Result<(), InputError> {
+    let pin_count = config.device_type.pin_count();
+
+    let selector_pins = config
+        .selectors
+        .iter()
+        .flat_map(|selector| selector.pins.iter());
+
+    match config
+        .pin_debounce_ms
+        .keys()
+        .chain(selector_pins)
+        .find(|&&pin| pin >= pin_count)
+    {
+        Some(pin) => Err(InputError::new(format!(
+            "Invalid pin {} for {}, a {:?} has {} pins",
+            pin, config.dev_name, config.device_type, pin_count
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Reading a pair in one transaction relies on the address pointer moving from A to B
+fn validate_read_mode(config: &DeviceConfig) -> Result<(), InputError> {
+    let iocon = config.iocon();
+    // The MCP23008 only has one bank
+    let bank = iocon.bank && config.device_type != ExpanderType::Mcp23008;
+
+    let problem = match config.read_mode {
+        _ if config.device_type.is_pcf857x() => None,
+        ReadMode::Word if bank => Some("IOCON.BANK"),
+        ReadMode::Block if bank || iocon.seqop => Some("IOCON.BANK and IOCON.SEQOP"),
+        _ => None,
+    };
+
+    match problem {
+        Some(bits) => Err(InputError::new(format!(
+            "{:?} reads on {} need {} clear",
+            config.read_mode, config.dev_name, bits
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Default)]
+struct InterruptRegisters {
+    enable_mask: u16,
+    control_mask: u16,
+    default_value: u16,
+}
+
+/// Configure an already opened device according to the given config
+pub fn setup_expander<D>(dev: D, config: &DeviceConfig) -> Result<Expander<D>, InputError>
+where
+    D: I2CDevice,
+    InputError: From<D::Error>,
+{
+    validate_pins(config)?;
+    validate_read_mode(config)?;
+
+    let pin_debounce = config
+        .pin_debounce_ms
+        .iter()
+        .map(|(&bit, &millis)| (bit, Duration::from_millis(millis)))
+        .collect();
+
+    let pin_mask = config.device_type.pin_mask();
+    let direction_mask = config.direction_mask & pin_mask;
+
+    // Interrupts are only enabled (on every input pin, unless configured otherwise) for devices
+    // wired up to raise them
+    let interrupt = match config.interrupt {
+        Some(ref interrupt) => InterruptRegisters {
+            enable_mask: interrupt.enable_mask.unwrap_or(direction_mask),
+            control_mask: interrupt.control_mask,
+            default_value: interrupt.default_value,
+        },
+        None => InterruptRegisters::default(),
+    };
+
+    let mut dev = Expander {
+        dev_name: config.dev_name.clone(),
+        dev_path: config.dev_path.clone(),
+        dev,
+        address: config.address,
+        device_type: config.device_type,
+        direction_mask,
+        polarity_mask: config.polarity_mask & pin_mask,
+        previous_value: 0,
+        output_latch: config.registers.olat.unwrap_or(0) & pin_mask,
+        iocon: config.iocon(),
+        gpinten: config.registers.gpinten.unwrap_or(interrupt.enable_mask) & direction_mask,
+        defval: config.registers.defval.unwrap_or(interrupt.default_value),
+        intcon: config.registers.intcon.unwrap_or(interrupt.control_mask),
+        interrupt_driven: config.interrupt.is_some(),
+        read_mode: config.read_mode,
+        debouncer: Debouncer::new(
+            &config.dev_name,
+            Duration::from_millis(config.debounce_ms),
+            pin_debounce,
+        ),
+        selectors: config
+            .selectors
+            .iter()
+            .cloned()
+            .map(Selector::new)
+            .collect(),
+        health: DeviceHealth::new(),
+    };
+
+    dev.configure()?;
+
+    // Perform a read to get the initial value. This also clears any pending interrupt
+    let current_value = dev.read_pins()?;
+    dev.previous_value = current_value;
+    dev.debouncer.reset(current_value);
+    for selector in dev.selectors.iter_mut() {
+        selector.reset(current_value);
+    }
+    Ok(dev)
+}
+
+fn open_expander(config: &DeviceConfig) -> Result<Expander, InputError> {
+    let dev = LinuxI2CDevice::new(&config.dev_path, config.address)?;
+    setup_expander(dev, config)
+}
+
+fn setup_devices(devices: &[&DeviceConfig]) -> Result<Vec<Expander>, InputError> {
+    devices.iter().map(|config| open_expander(config)).collect()
+}
+
+fn open_ads1x15(config: &AdcConfig) -> Result<ADS1x15<LinuxI2CDevice>, InputError> {
+    let dev = LinuxI2CDevice::new(&config.dev_path, config.address)?;
+    setup_ads1x15(dev, config)
+}
+
+fn open_ht16k33(config: &DisplayConfig) -> Result<HT16K33<LinuxI2CDevice>, InputError> {
+    let dev = LinuxI2CDevice::new(&config.dev_path, config.address)?;
+    setup_ht16k33(dev, config)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::input::ads1x15::AdcModel;
     use crate::input::bitevents::BitEvent;
-    use crate::input::mcp23017::compute_new_values;
+    use crate::input::emulator::{
+        EmulatedMCP23017, EmulatedPCF857x, DEFVAL, GPINTEN, GPPU, INTCON, IOCON, IPOL, OLAT,
+    };
+    use crate::input::mcp23017::config::{
+        DeviceConfig, ExpanderType, InterruptConfig, PanelDeviceConfig, ReadMode, RegisterConfig,
+    };
+    use crate::input::mcp23017::registers::Iocon;
+    use crate::input::mcp23017::{compute_new_values, setup_expander, Level, PanelInputHandler};
+    use crate::input::selector::{Encoding, SelectorConfig};
+    use crate::input::{InputError, InputHandler};
+    use maplit::btreemap;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    fn emulated_config(dev_name: &str, direction_mask: u16) -> DeviceConfig {
+        DeviceConfig {
+            device_type: ExpanderType::Mcp23017,
+            dev_path: PathBuf::from("/dev/null"),
+            dev_name: String::from(dev_name),
+            address: 0x20,
+            polarity_mask: 0x0000,
+            direction_mask,
+            interrupt: None,
+            debounce_ms: 0,
+            pin_debounce_ms: BTreeMap::new(),
+            selectors: vec![],
+            registers: RegisterConfig::default(),
+            read_mode: ReadMode::default(),
+        }
+    }
+
+    // Pollers report whatever they have seen so far, so keep reading until enough events arrive
+    fn read_at_least(
+        handler: &mut dyn InputHandler,
+        count: usize,
+    ) -> Result<Vec<BitEvent>, InputError> {
+        let mut events = Vec::new();
+        while events.len() < count {
+            events.append(&mut handler.read_events()?);
+        }
+        Ok(events)
+    }
 
     #[test]
     fn test_compute_set_values() {
+        let new_value =
+            compute_new_values(0, &[(1, Level::High), (3, Level::High), (5, Level::High)]);
+
+        assert!(new_value == 0b0000000000101010);
+    }
+
+    #[test]
+    fn test_compute_unset_values() {
+        let new_value =
+            compute_new_values(0xffff, &[(1, Level::Low), (3, Level::Low), (5, Level::Low)]);
+
+        assert!(new_value == 0b1111111111010101);
+    }
+
+    #[test]
+    fn test_compute_mixed_values() {
         let new_value = compute_new_values(
-            0,
+            0x00ff,
             &[
+                (1, Level::Low),
+                (3, Level::High),
+                (9, Level::Low),
+                (11, Level::High),
+            ],
+        );
+
+        assert!(new_value == 0b0000100011111101);
+    }
+
+    #[test]
+    fn test_emulated_poll() {
+        let chip = EmulatedMCP23017::new();
+        let dev = setup_expander(chip.clone(), &emulated_config("test", 0xffff)).unwrap();
+        let mut handler = PanelInputHandler::from_devices(vec![dev]);
+
+        // Pulled up and inverted, so grounding a pin reads as "on"
+        chip.set_pin(4, false);
+
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("test"),
+                bit: 4,
+                value: 1,
+            }])
+        );
+
+        chip.release_pin(4);
+        chip.set_pin(12, false);
+
+        assert_eq!(
+            read_at_least(&mut handler, 2),
+            Ok(vec![
                 BitEvent {
                     dev_name: String::from("test"),
-                    bit: 1,
-                    value: 1,
+                    bit: 4,
+                    value: 0,
                 },
                 BitEvent {
                     dev_name: String::from("test"),
-                    bit: 3,
+                    bit: 12,
+                    value: 1,
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_emulated_set_output() {
+        let inputs = EmulatedMCP23017::new();
+        let outputs = EmulatedMCP23017::new();
+        let more_outputs = EmulatedMCP23017::new();
+        let mut handler = PanelInputHandler::from_devices(vec![
+            setup_expander(inputs.clone(), &emulated_config("inputs", 0xffff)).unwrap(),
+            setup_expander(outputs.clone(), &emulated_config("outputs", 0xff00)).unwrap(),
+            setup_expander(
+                more_outputs.clone(),
+                &emulated_config("more_outputs", 0x0000),
+            )
+            .unwrap(),
+        ]);
+
+        handler
+            .set_output(&[
+                BitEvent {
+                    dev_name: String::from("outputs"),
+                    bit: 0,
                     value: 1,
                 },
                 BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 5,
+                    dev_name: String::from("outputs"),
+                    bit: 6,
                     value: 1,
                 },
-            ],
-        );
+            ])
+            .unwrap();
 
-        assert!(new_value == 0b0000000000101010);
-    }
+        assert_eq!(outputs.outputs(), 0x0041);
+        assert_eq!(inputs.outputs(), 0x0000);
 
-    #[test]
-    fn test_compute_unset_values() {
-        let new_value = compute_new_values(
-            0xffff,
-            &[
+        // Unknown devices and input pins are rejected, without writing the rest of the batch
+        assert!(handler
+            .set_output(&[
                 BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 1,
+                    dev_name: String::from("outputs"),
+                    bit: 0,
                     value: 0,
                 },
                 BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 3,
+                    dev_name: String::from("nowhere"),
+                    bit: 0,
                     value: 0,
-                },
+                }
+            ])
+            .is_err());
+        assert!(handler
+            .set_output(&[BitEvent {
+                dev_name: String::from("outputs"),
+                bit: 8,
+                value: 1,
+            }])
+            .is_err());
+        assert_eq!(outputs.outputs(), 0x0041);
+
+        // One batch can span several devices
+        handler
+            .set_output(&[
                 BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 5,
+                    dev_name: String::from("outputs"),
+                    bit: 6,
                     value: 0,
                 },
-            ],
-        );
+                BitEvent {
+                    dev_name: String::from("more_outputs"),
+                    bit: 3,
+                    value: 1,
+                },
+            ])
+            .unwrap();
 
-        assert!(new_value == 0b1111111111010101);
+        assert_eq!(outputs.outputs(), 0x0001);
+        assert_eq!(more_outputs.outputs(), 0x0008);
     }
 
     #[test]
-    fn test_compute_mixed_values() {
-        let new_value = compute_new_values(
-            0x00ff,
-            &[
-                BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 1,
-                    value: 0,
-                },
+    fn test_emulated_interrupt() {
+        let polled = EmulatedMCP23017::new();
+        let interrupting = EmulatedMCP23017::new();
+
+        let mut interrupt_config = emulated_config("interrupting", 0xffff);
+        interrupt_config.interrupt = Some(InterruptConfig {
+            chip: PathBuf::from("/dev/null"),
+            line: 0,
+            line_b: None,
+            enable_mask: None,
+            control_mask: 0x0000,
+            default_value: 0x0000,
+            mirror: true,
+            open_drain: false,
+        });
+
+        let (tx, rx) = channel();
+        let mut handler = PanelInputHandler::start(
+            vec![
+                setup_expander(polled.clone(), &emulated_config("polled", 0xffff)).unwrap(),
+                setup_expander(interrupting.clone(), &interrupt_config).unwrap(),
+            ],
+            vec![],
+            vec![],
+            btreemap! { PathBuf::from("/dev/null") => rx },
+            vec![],
+        );
+
+        // A tap that is over before anyone looks is still reported, while the interrupt-driven
+        // device is left alone until it raises an interrupt
+        interrupting.set_pin(3, false);
+        interrupting.set_pin(3, true);
+        polled.set_pin(0, false);
+        assert!(interrupting.interrupt_raised());
+
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("polled"),
+                bit: 0,
+                value: 1,
+            }])
+        );
+
+        tx.send(1).unwrap();
+
+        assert_eq!(
+            read_at_least(&mut handler, 2),
+            Ok(vec![
                 BitEvent {
-                    dev_name: String::from("test"),
+                    dev_name: String::from("interrupting"),
                     bit: 3,
                     value: 1,
                 },
                 BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 9,
+                    dev_name: String::from("interrupting"),
+                    bit: 3,
+                    value: 0,
+                }
+            ])
+        );
+        assert!(!interrupting.interrupt_raised());
+    }
+
+    #[test]
+    fn test_interrupt_watchers_stopped() {
+        let chip = EmulatedMCP23017::new();
+
+        let mut config = emulated_config("test", 0xffff);
+        config.interrupt = Some(InterruptConfig {
+            chip: PathBuf::from("/dev/null"),
+            line: 0,
+            line_b: None,
+            enable_mask: None,
+            control_mask: 0x0000,
+            default_value: 0x0000,
+            mirror: true,
+            open_drain: false,
+        });
+
+        // The watcher is gone before the poller starts waiting on it
+        let (tx, rx) = channel::<usize>();
+        drop(tx);
+        let mut handler = PanelInputHandler::start(
+            vec![setup_expander(chip.clone(), &config).unwrap()],
+            vec![],
+            vec![],
+            btreemap! { PathBuf::from("/dev/null") => rx },
+            vec![],
+        );
+
+        // So the device is polled instead
+        chip.set_pin(3, false);
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("test"),
+                bit: 3,
+                value: 1,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_emulated_debounce() {
+        let chip = EmulatedMCP23017::new();
+
+        let mut config = emulated_config("test", 0xffff);
+        config.debounce_ms = 30;
+        config.pin_debounce_ms = btreemap! { 5 => 0 };
+
+        let dev = setup_expander(chip.clone(), &config).unwrap();
+        let mut handler = PanelInputHandler::from_devices(vec![dev]);
+
+        // Pin 5 is not debounced, so it comes through on the first poll
+        chip.set_pin(5, false);
+
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("test"),
+                bit: 5,
+                value: 1,
+            }])
+        );
+
+        let start = Instant::now();
+        chip.set_pin(2, false);
+
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("test"),
+                bit: 2,
+                value: 1,
+            }])
+        );
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_emulated_selector() {
+        let chip = EmulatedMCP23017::new();
+
+        let mut config = emulated_config("test", 0xffff);
+        config.selectors = vec![SelectorConfig {
+            name: String::from("mode"),
+            pins: vec![8, 9, 10],
+            encoding: Encoding::Gray,
+            settle_ms: 0,
+        }];
+
+        let dev = setup_expander(chip.clone(), &config).unwrap();
+        let mut handler = PanelInputHandler::from_devices(vec![dev]);
+
+        // Pins read active low, so pulling 8 and 9 down is Gray code 0b011
+        chip.set_pin(8, false);
+        chip.set_pin(9, false);
+
+        assert_eq!(
+            read_at_least(&mut handler, 2),
+            Ok(vec![
+                BitEvent {
+                    dev_name: String::from("mode"),
+                    bit: 0,
                     value: 0,
                 },
                 BitEvent {
-                    dev_name: String::from("test"),
-                    bit: 11,
+                    dev_name: String::from("mode"),
+                    bit: 2,
                     value: 1,
-                },
-            ],
+                }
+            ])
         );
-
-        println!("{:016b} => {:016b}", 0x00ff, new_value);
-        assert!(new_value == 0b0000100011111101);
     }
-}
 
-impl<'config> InputHandler for PanelInputHandler {
-    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
-        poll_inputs(self)
-    }
+    #[test]
+    fn test_parse_mixed_devices() {
+        let devices: Vec<PanelDeviceConfig> = serde_yaml::from_str(
+            "
+- dev_path: /dev/i2c-1
+  dev_name: main_a
+  address: 0x20
+  polarity_mask: 0x0000
+  direction_mask: 0xffff
+- device_type: ads1115
+  dev_path: /dev/i2c-1
+  dev_name: pots
+  address: 0x48
+  channels:
+    - channel: 0
+      max_volts: 3.3
+      thresholds: [64, 128, 192]
+- device_type: ht16k33
+  dev_path: /dev/i2c-1
+  dev_name: met
+  address: 0x70
+",
+        )
+        .unwrap();
 
-    fn set_output(&mut self, dev_index: usize, bits: &[BitEvent]) -> Result<(), InputError> {
-        if dev_index >= self.devices.len() {
-            Err(InputError {
-                message: format!(
-                    "Invalid device index {}. Number of devices {}",
-                    dev_index,
-                    self.devices.len()
-                ),
-            })
-        } else {
-            let dev: &mut MCP23017 = &mut self.devices[dev_index];
-            if bits.iter().any(|event| event.bit > 15) {
-                Err(InputError {
-                    message: format!("Invalid bit events: {:?}", bits),
-                })
-            } else {
-                let new_value: u16 = compute_new_values((*dev).read_pins()?, bits);
-                Ok((*dev).write_pins(new_value)?) // I hate this pattern
+        let mut expander = emulated_config("main_a", 0xffff);
+        expander.dev_path = PathBuf::from("/dev/i2c-1");
+        assert_eq!(devices[0], PanelDeviceConfig::Expander(expander));
+        match &devices[1] {
+            PanelDeviceConfig::Adc(adc) => {
+                assert_eq!(adc.device_type, AdcModel::Ads1115);
+                assert_eq!(adc.poll_ms, 100);
+                assert_eq!(adc.channels[0].range_volts, 4.096);
+                assert_eq!(adc.channels[0].full_scale, 255);
+                assert_eq!(adc.channels[0].thresholds, vec![64, 128, 192]);
+            }
+            other => panic!("Expected an ADC, got {:?}", other),
+        }
+        match &devices[2] {
+            PanelDeviceConfig::Display(display) => {
+                assert_eq!(display.digit_rows, vec![0, 1, 3, 4]);
+                assert_eq!(display.colon_row, Some(2));
+                assert_eq!(display.brightness, 15);
             }
+            other => panic!("Expected a display, got {:?}", other),
         }
     }
 
-    fn shutdown(self) {
-        debug!("Shutdown is NOOP on MCP23017");
-    }
-}
+    #[test]
+    fn test_parse_device_errors() {
+        let parse = |config| serde_yaml::from_str::<Vec<PanelDeviceConfig>>(config);
 
-impl From<LinuxI2CError> for InputError {
-    fn from(err: LinuxI2CError) -> InputError {
-        InputError {
-            message: format!("I2C Error: {}", err),
-        }
+        // Mistakes are reported against the kind of device given
+        let missing = parse(
+            "
+- device_type: ads1115
+  dev_path: /dev/i2c-1
+  dev_name: pots
+  address: 0x48
+",
+        );
+        assert!(missing.unwrap_err().to_string().contains("channels"));
+
+        let unknown = parse(
+            "
+- device_type: mcp23018
+  dev_path: /dev/i2c-1
+  dev_name: main_a
+  address: 0x20
+",
+        );
+        assert!(unknown.unwrap_err().to_string().contains("mcp23018"));
     }
-}
 
-// Important registers, assuming IOCON.BANK == 0
-const IODIR: u8 = 0x00;
-const IPOL: u8 = 0x02;
-const GPPU: u8 = 0x0C;
-const GPIO: u8 = 0x12;
+    #[test]
+    fn test_emulated_pcf8574() {
+        let chip = EmulatedPCF857x::new(8);
 
-pub struct MCP23017 {
-    dev_name: String,
-    dev: LinuxI2CDevice,
-    address: u16,
-    previous_value: u16,
-}
+        let mut config = emulated_config("pcf", 0x000f);
+        config.device_type = ExpanderType::Pcf8574;
 
-impl MCP23017 {
-    pub fn poll_input(&mut self) -> Result<Vec<BitEvent>, LinuxI2CError> {
-        let new_value = self.read_pins()?;
+        let dev = setup_expander(chip.clone(), &config).unwrap();
+        // Inputs are left high, outputs start low
+        assert_eq!(chip.latch(), 0x000f);
 
-        debug!("Current for {} : {:#x}", self.dev_name, new_value);
-        debug!("Prev for {}    : {:#x}", self.dev_name, self.previous_value);
+        let mut handler = PanelInputHandler::from_devices(vec![dev]);
 
-        let events = bit_compare(&self.dev_name, self.previous_value, new_value);
-        self.previous_value = new_value;
-        Ok(events)
+        chip.set_pin(2, false);
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("pcf"),
+                bit: 2,
+                value: 1,
+            }])
+        );
+
+        handler
+            .set_output(&[BitEvent {
+                dev_name: String::from("pcf"),
+                bit: 5,
+                value: 1,
+            }])
+            .unwrap();
+        assert_eq!(chip.latch(), 0x002f);
+
+        // There is no pin 8 on an 8 pin device
+        assert!(handler
+            .set_output(&[BitEvent {
+                dev_name: String::from("pcf"),
+                bit: 8,
+                value: 1,
+            }],)
+            .is_err());
     }
 
-    pub fn read_pins(&mut self) -> Result<u16, LinuxI2CError> {
-        let mut result = u16::from(self.dev.smbus_read_byte_data(GPIO)?);
-        result |= u16::from(self.dev.smbus_read_byte_data(GPIO + 1)?) << 8;
-        debug!("Read 0x{:04x} from 0x{:02x}", result, self.address);
-        Ok(result)
+    #[test]
+    fn test_invalid_pins() {
+        let mut config = emulated_config("small", 0x00ff);
+        config.device_type = ExpanderType::Mcp23008;
+        config.pin_debounce_ms = btreemap! { 9 => 10 };
+
+        assert!(setup_expander(EmulatedMCP23017::new(), &config).is_err());
     }
 
-    pub fn write_pins(&mut self, new_values: u16) -> Result<(), LinuxI2CError> {
-        self.dev.smbus_write_word_data(GPIO, new_values)
+    #[test]
+    fn test_emulated_device_recovery() {
+        let flaky = EmulatedMCP23017::new();
+        let steady = EmulatedMCP23017::new();
+
+        let mut handler = PanelInputHandler::from_devices(vec![
+            setup_expander(flaky.clone(), &emulated_config("flaky", 0xffff)).unwrap(),
+            setup_expander(steady.clone(), &emulated_config("steady", 0xffff)).unwrap(),
+        ]);
+
+        flaky.disconnect();
+        steady.set_pin(1, false);
+
+        // The rest of the bus carries on without it
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("steady"),
+                bit: 1,
+                value: 1,
+            }])
+        );
+
+        // Comes back after a power cycle, with a switch flipped while it was away
+        flaky.set_pin(4, false);
+        flaky.reconnect();
+
+        assert_eq!(
+            handler.read_events(),
+            Ok(vec![BitEvent {
+                dev_name: String::from("flaky"),
+                bit: 4,
+                value: 1,
+            }])
+        );
+        assert_eq!(flaky.register(IPOL), 0xffff);
+        assert_eq!(flaky.register(GPPU), 0xffff);
     }
-}
 
-pub fn setup_mcp23017(config: &DeviceConfig) -> Result<MCP23017, LinuxI2CError> {
-    let mut dev = LinuxI2CDevice::new(&config.dev_path, config.address)?;
+    #[test]
+    fn test_emulated_output_latch() {
+        let chip = EmulatedMCP23017::new();
+        let mut config = emulated_config("mixed", 0x00ff);
+        // Inverted inputs, so GPIO reads back levels that don't belong in the latch
+        config.polarity_mask = 0x0000;
+        let mut dev = setup_expander(chip.clone(), &config).unwrap();
+        chip.set_pin(3, false);
 
-    // Set the IO Direction registers. Also enable the pullup on any input pins
-    dev.smbus_write_word_data(IODIR, config.direction_mask)?;
-    dev.smbus_write_word_data(GPPU, config.direction_mask)?;
+        dev.set_pins(&[(8, Level::High), (12, Level::High)])
+            .unwrap();
+        dev.set_pins(&[(8, Level::Low)]).unwrap();
 
-    // Set the polarity mask
-    dev.smbus_write_word_data(IPOL, !config.polarity_mask)?;
+        // The input levels stay out of the output latch
+        assert_eq!(chip.register(OLAT), 0x1000);
+        assert_eq!(chip.outputs(), 0x1000);
 
-    let mut dev = MCP23017 {
-        dev_name: config.dev_name.clone(),
-        dev,
-        address: config.address,
-        previous_value: 0,
-    };
+        // A power cycle clears the latch, recovery puts it back
+        chip.reconnect();
+        assert_eq!(chip.outputs(), 0x0000);
+        dev.recover().unwrap();
+        assert_eq!(chip.outputs(), 0x1000);
+    }
 
-    // Perform a read to get the initial value
-    let current_value = dev.read_pins()?;
-    dev.previous_value = current_value;
-    Ok(dev)
-}
+    #[test]
+    fn test_declared_registers() {
+        let chip = EmulatedMCP23017::new();
+        let mut config: DeviceConfig = serde_yaml::from_str(
+            "
+dev_path: /dev/null
+dev_name: declared
+address: 0x20
+polarity_mask: 0x0000
+direction_mask: 0x00ff
+registers:
+  iocon:
+    seqop: true
+    intpol: true
+  gpinten: 0xff0f
+  defval: 0x00f0
+  intcon: 0x00f0
+  olat: 0xa500
+",
+        )
+        .unwrap();
 
-fn setup_devices(devices: &[DeviceConfig]) -> Result<Vec<MCP23017>, LinuxI2CError> {
-    devices.iter().map(&setup_mcp23017).collect()
-}
+        setup_expander(chip.clone(), &config).unwrap();
 
-fn poll_inputs(state: &mut PanelInputHandler) -> Result<Vec<BitEvent>, InputError> {
-    loop {
-        let mut events: Vec<BitEvent> = Vec::new();
+        assert_eq!(chip.register(IOCON), 0x2222);
+        // Only input pins can raise interrupts
+        assert_eq!(chip.register(GPINTEN), 0x000f);
+        assert_eq!(chip.register(DEFVAL), 0x00f0);
+        assert_eq!(chip.register(INTCON), 0x00f0);
+        assert_eq!(chip.outputs(), 0xa500);
 
-        for dev in &mut state.devices {
-            let mut inputs = dev.poll_input()?;
-            events.append(&mut inputs);
-        }
+        // The interrupt config fills in the rest of IOCON
+        config.interrupt = Some(InterruptConfig {
+            chip: PathBuf::from("/dev/null"),
+            line: 0,
+            line_b: None,
+            enable_mask: None,
+            control_mask: 0x0000,
+            default_value: 0x0000,
+            mirror: true,
+            open_drain: false,
+        });
+        assert_eq!(config.iocon().bits(), 0x62);
+    }
 
-        if !events.is_empty() {
-            return Ok(events);
+    #[test]
+    fn test_read_modes() {
+        // Transactions for a poll, then for an interrupt
+        let expected = [
+            (ReadMode::Bytes, 2, 6),
+            (ReadMode::Word, 1, 3),
+            (ReadMode::Block, 1, 1),
+        ];
+
+        for &(read_mode, poll_reads, interrupt_reads) in &expected {
+            let chip = EmulatedMCP23017::new();
+            let mut config = emulated_config("test", 0xffff);
+            config.read_mode = read_mode;
+            config.registers.gpinten = Some(0xffff);
+            let mut dev = setup_expander(chip.clone(), &config).unwrap();
+
+            chip.set_pin(9, false);
+            let reads = chip.reads();
+            assert_eq!(dev.poll_input().unwrap().len(), 1);
+            assert_eq!(chip.reads() - reads, poll_reads, "{:?}", read_mode);
+
+            // A tap on port A that is already over
+            chip.set_pin(2, false);
+            chip.set_pin(2, true);
+            let reads = chip.reads();
+            assert_eq!(
+                dev.read_interrupt().unwrap(),
+                vec![
+                    BitEvent {
+                        dev_name: String::from("test"),
+                        bit: 2,
+                        value: 1,
+                    },
+                    BitEvent {
+                        dev_name: String::from("test"),
+                        bit: 2,
+                        value: 0,
+                    }
+                ]
+            );
+            assert_eq!(chip.reads() - reads, interrupt_reads, "{:?}", read_mode);
         }
 
-        thread::sleep(POLL_TIME);
+        // Single transaction reads rely on the address pointer moving from A to B
+        let mut config = emulated_config("test", 0xffff);
+        config.read_mode = ReadMode::Word;
+        config.registers.iocon.bank = Some(true);
+        assert!(setup_expander(EmulatedMCP23017::new(), &config).is_err());
+
+        config.registers.iocon.bank = None;
+        config.registers.iocon.seqop = Some(true);
+        config.read_mode = ReadMode::Block;
+        assert!(setup_expander(EmulatedMCP23017::new(), &config).is_err());
+    }
+
+    #[test]
+    fn test_bank_1_setup() {
+        let chip = EmulatedMCP23017::new();
+        let mut config = emulated_config("bank", 0xff00);
+        config.registers.iocon.bank = Some(true);
+        config.read_mode = ReadMode::Bytes;
+        let mut dev = setup_expander(chip.clone(), &config).unwrap();
+
+        assert_eq!(chip.register(IOCON) & 0xff, u16::from(Iocon::BANK));
+        dev.set_pins(&[(0, Level::High), (7, Level::High)]).unwrap();
+        assert_eq!(chip.outputs(), 0x0081);
+
+        // Already in BANK 1, the first IOCON write lands on OLATA
+        dev.write_iocon().unwrap();
+        assert_eq!(chip.outputs(), 0x0081);
+    }
+
+    #[test]
+    fn test_short_block_read() {
+        let chip = EmulatedMCP23017::new();
+        let mut config = emulated_config("test", 0xffff);
+        config.read_mode = ReadMode::Block;
+        config.registers.gpinten = Some(0xffff);
+        let mut dev = setup_expander(chip.clone(), &config).unwrap();
+
+        // GPIO is missing, which mustn't be taken as every pin going low
+        chip.limit_block_reads(4);
+        chip.set_pin(2, false);
+        assert!(dev.read_interrupt().is_err());
+    }
+
+    #[test]
+    fn test_emulated_current_state() {
+        let chip = EmulatedMCP23017::new();
+        chip.set_pin(0, false);
+        chip.set_pin(5, false);
+
+        // Only the low byte is input, with pins 4-6 making up a selector
+        let mut config = emulated_config("test", 0x00ff);
+        config.selectors = vec![SelectorConfig {
+            name: String::from("mode"),
+            pins: vec![4, 5, 6],
+            encoding: Encoding::OneHot,
+            settle_ms: 0,
+        }];
+
+        let dev = setup_expander(chip.clone(), &config).unwrap();
+        let mut handler = PanelInputHandler::from_devices(vec![dev]);
+
+        let event = |bit, value| BitEvent {
+            dev_name: String::from("test"),
+            bit,
+            value,
+        };
+
+        assert_eq!(
+            handler.current_state(),
+            Ok(vec![
+                event(0, 1),
+                event(1, 0),
+                event(2, 0),
+                event(3, 0),
+                event(7, 0),
+                BitEvent {
+                    dev_name: String::from("mode"),
+                    bit: 1,
+                    value: 1,
+                },
+            ])
+        );
     }
 }
@@ -0,0 +1,28 @@
+//! PCF8574/PCF8575 access. These have no registers: reading returns the pin levels and writing
+//! sets the output latch, with a high latch doubling as a weakly pulled up input.
+
+use i2cdev::core::I2CDevice;
+
+use super::config::ExpanderType;
+
+/// Read all pins, P0x in the low byte
+pub fn read_port<D: I2CDevice>(dev: &mut D, device_type: ExpanderType) -> Result<u16, D::Error> {
+    let mut data = [0u8; 2];
+    let len = port_bytes(device_type);
+
+    dev.read(&mut data[..len])?;
+    Ok(u16::from(data[0]) | u16::from(data[1]) << 8)
+}
+
+pub fn write_port<D: I2CDevice>(
+    dev: &mut D,
+    device_type: ExpanderType,
+    value: u16,
+) -> Result<(), D::Error> {
+    let data = [value as u8, (value >> 8) as u8];
+    dev.write(&data[..port_bytes(device_type)])
+}
+
+fn port_bytes(device_type: ExpanderType) -> usize {
+    usize::from(device_type.pin_count() / 8)
+}
@@ -0,0 +1,148 @@
+//! The MCP23017 register map, shared by the driver and the utilities in src/bin. The MCP23008 has
+//! the same registers, for a single port.
+
+// Named as in the datasheet
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    IODIR,
+    IPOL,
+    GPINTEN,
+    DEFVAL,
+    INTCON,
+    IOCON,
+    GPPU,
+    INTF,
+    INTCAP,
+    GPIO,
+    OLAT,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Port {
+    A,
+    B,
+}
+
+impl Register {
+    #[allow(dead_code)] // Used by read_mcp23017
+    pub const ALL: [Register; 11] = [
+        Register::IODIR,
+        Register::IPOL,
+        Register::GPINTEN,
+        Register::DEFVAL,
+        Register::INTCON,
+        Register::IOCON,
+        Register::GPPU,
+        Register::INTF,
+        Register::INTCAP,
+        Register::GPIO,
+        Register::OLAT,
+    ];
+
+    /// Where the register sits in each port's block, which is also its MCP23008 address
+    pub const fn index(self) -> u8 {
+        self as u8
+    }
+
+    /// The register's address for one port. With IOCON.BANK clear the A and B registers are
+    /// paired up, with it set each port has its own block
+    pub const fn address(self, port: Port, bank: bool) -> u8 {
+        match (bank, port) {
+            (false, Port::A) => self.index() * 2,
+            (false, Port::B) => self.index() * 2 + 1,
+            (true, Port::A) => self.index(),
+            (true, Port::B) => self.index() + 0x10,
+        }
+    }
+
+    #[allow(dead_code)] // Used by read_mcp23017
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::IODIR => "IODIR",
+            Register::IPOL => "IPOL",
+            Register::GPINTEN => "GPINTEN",
+            Register::DEFVAL => "DEFVAL",
+            Register::INTCON => "INTCON",
+            Register::IOCON => "IOCON",
+            Register::GPPU => "GPPU",
+            Register::INTF => "INTF",
+            Register::INTCAP => "INTCAP",
+            Register::GPIO => "GPIO",
+            Register::OLAT => "OLAT",
+        }
+    }
+}
+
+/// The IOCON bits. There is only one IOCON, it shows up at both port addresses
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Iocon {
+    // Separate register blocks for each port
+    pub bank: bool,
+    // INTA and INTB both report either port
+    pub mirror: bool,
+    // Don't increment the address pointer
+    pub seqop: bool,
+    // Open drain interrupt pins, which overrides intpol
+    pub odr: bool,
+    // Active high interrupt pins
+    pub intpol: bool,
+}
+
+impl Iocon {
+    pub const BANK: u8 = 0x80;
+    pub const MIRROR: u8 = 0x40;
+    pub const SEQOP: u8 = 0x20;
+    pub const ODR: u8 = 0x04;
+    pub const INTPOL: u8 = 0x02;
+
+    pub fn bits(self) -> u8 {
+        [
+            (self.bank, Iocon::BANK),
+            (self.mirror, Iocon::MIRROR),
+            (self.seqop, Iocon::SEQOP),
+            (self.odr, Iocon::ODR),
+            (self.intpol, Iocon::INTPOL),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    }
+
+    #[allow(dead_code)] // Used by read_mcp23017
+    pub fn from_bits(bits: u8) -> Iocon {
+        Iocon {
+            bank: bits & Iocon::BANK != 0,
+            mirror: bits & Iocon::MIRROR != 0,
+            seqop: bits & Iocon::SEQOP != 0,
+            odr: bits & Iocon::ODR != 0,
+            intpol: bits & Iocon::INTPOL != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Iocon, Port, Register};
+
+    #[test]
+    fn test_addresses() {
+        assert_eq!(Register::GPIO.address(Port::A, false), 0x12);
+        assert_eq!(Register::GPIO.address(Port::B, false), 0x13);
+        assert_eq!(Register::GPIO.address(Port::A, true), 0x09);
+        assert_eq!(Register::GPIO.address(Port::B, true), 0x19);
+        assert_eq!(Register::OLAT.index(), 0x0a);
+    }
+
+    #[test]
+    fn test_iocon_bits() {
+        let iocon = Iocon {
+            bank: true,
+            odr: true,
+            ..Iocon::default()
+        };
+
+        assert_eq!(iocon.bits(), 0x84);
+        assert_eq!(Iocon::from_bits(0x84), iocon);
+    }
+}
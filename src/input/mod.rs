@@ -1,25 +1,54 @@
+pub mod ads1x15;
 pub mod bitevents;
+pub mod composite;
+pub mod debounce;
+#[cfg(test)]
+pub mod emulator;
+pub mod event_device;
 pub mod mcp23017;
+pub mod network;
+pub mod repl;
+pub mod replay;
+pub mod selector;
 pub mod stdin;
 
 use self::bitevents::BitEvent;
 
 use std::fmt::{Display, Error, Formatter};
 use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
 
 #[derive(Debug, PartialEq)]
 pub struct InputError {
     message: String,
+    // The input has been closed for good, so there's nothing left to run
+    closed: bool,
 }
 
 impl InputError {
     pub fn new(message: String) -> InputError {
-        InputError { message }
+        InputError {
+            message,
+            closed: false,
+        }
     }
 
     pub fn from_str(message: &str) -> InputError {
         InputError::new(message.to_string())
     }
+
+    /// The input has been closed on purpose, such as with Ctrl-C, and the program should stop
+    pub fn closed(message: &str) -> InputError {
+        InputError {
+            closed: true,
+            ..InputError::from_str(message)
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
 }
 
 impl Display for InputError {
@@ -30,9 +59,7 @@ impl Display for InputError {
 
 impl From<io::Error> for InputError {
     fn from(err: io::Error) -> InputError {
-        InputError {
-            message: format!("Error: {}", err),
-        }
+        InputError::new(format!("Error: {}", err))
     }
 }
 
@@ -40,24 +67,91 @@ use std::num::{ParseFloatError, ParseIntError};
 
 impl From<ParseIntError> for InputError {
     fn from(err: ParseIntError) -> InputError {
-        InputError {
-            message: format!("Error: {}", err),
-        }
+        InputError::new(format!("Error: {}", err))
     }
 }
 
 impl From<ParseFloatError> for InputError {
     fn from(err: ParseFloatError) -> InputError {
-        InputError {
-            message: format!("Error: {}", err),
-        }
+        InputError::new(format!("Error: {}", err))
+    }
+}
+
+/// Cuts a backend's read short from another thread, so that whoever is waiting on its inputs can
+/// be woken up for something else. The read returns with no events
+pub struct Waker(Box<dyn Fn() + Send>);
+
+impl Waker {
+    pub fn new<F: Fn() + Send + 'static>(wake: F) -> Waker {
+        Waker(Box::new(wake))
+    }
+
+    pub fn wake(&self) {
+        (self.0)()
     }
 }
 
 pub trait InputHandler {
+    /// Wait as long as it takes for the next events, or until woken
     fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError>;
 
-    fn set_output(&mut self, dev_index: usize, bits: &[BitEvent]) -> Result<(), InputError>;
+    /// Like read_events, but gives up with no events once `timeout` has passed
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError>;
+
+    /// Wakes up a read in progress, or the next one if there isn't one
+    fn waker(&self) -> Waker;
+
+    /// Set output pins, each on the device named by the event
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError>;
+
+    /// Check that set_output would take every one of `bits`, without setting any of them
+    fn check_output(&mut self, _bits: &[BitEvent]) -> Result<(), InputError> {
+        Ok(())
+    }
+
+    /// The current level of every input the backend knows about, so that the simulator can start
+    /// out knowing where the switches are. Backends without any such state report nothing
+    fn current_state(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        Ok(Vec::new())
+    }
+
+    /// The names of the devices whose outputs this backend drives
+    fn output_devices(&self) -> Vec<String> {
+        Vec::new()
+    }
 
     fn shutdown(self);
 }
+
+/// Wake a backend that waits on batches from its reader thread(s) by sending it an empty one
+fn batch_waker(tx: &Sender<Vec<BitEvent>>) -> Waker {
+    let tx = tx.clone();
+    Waker::new(move || {
+        let _ = tx.send(Vec::new());
+    })
+}
+
+/// Wait for a batch of events from a backend's reader thread(s), forever if there's no timeout,
+/// and merge in any other batches that are already waiting
+fn receive_batches(
+    rx: &Receiver<Vec<BitEvent>>,
+    timeout: Option<Duration>,
+    stopped: &str,
+) -> Result<Vec<BitEvent>, InputError> {
+    let received = match timeout {
+        Some(timeout) => rx.recv_timeout(timeout),
+        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+
+    let mut events = match received {
+        Ok(events) => events,
+        Err(RecvTimeoutError::Timeout) => return Ok(Vec::new()),
+        Err(RecvTimeoutError::Disconnected) => return Err(InputError::from_str(stopped)),
+    };
+
+    for mut more in rx.try_iter() {
+        events.append(&mut more);
+    }
+
+    Ok(events)
+}
@@ -0,0 +1,216 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use super::bitevents::{parse_bit_events, BitEvent};
+use super::*;
+
+// Large enough for any reasonable batch of events in one datagram
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+const STOPPED: &str = "Network listener has stopped";
+
+/// Accepts the same "dev:bit:value[,...]" lines as StdinInput, from any number of TCP clients or
+/// as UDP datagrams. TCP clients get an "ok" or "error: ..." line back for each line they send.
+pub struct NetworkInput {
+    tx: Sender<Vec<BitEvent>>,
+    rx: Receiver<Vec<BitEvent>>,
+    local_addr: SocketAddr,
+}
+
+impl NetworkInput {
+    /// Listen according to a "tcp:<address>" or "udp:<address>" spec
+    pub fn new(spec: &str) -> Result<NetworkInput, InputError> {
+        match spec.split_at(spec.find(':').unwrap_or(0)) {
+            ("tcp", address) => NetworkInput::tcp(&address[1..]),
+            ("udp", address) => NetworkInput::udp(&address[1..]),
+            _ => Err(InputError::new(format!(
+                "Invalid network spec '{}', expected tcp:<address> or udp:<address>",
+                spec
+            ))),
+        }
+    }
+
+    pub fn tcp(address: &str) -> Result<NetworkInput, InputError> {
+        let listener = TcpListener::bind(address)?;
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = channel();
+
+        info!("Listening for events on tcp:{}", local_addr);
+
+        let listener_tx = tx.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tx = listener_tx.clone();
+                        thread::spawn(move || {
+                            handle_client(stream, tx).unwrap_or_else(|err| {
+                                warn!("Error handling network client: {}", err.message);
+                            });
+                        });
+                    }
+                    Err(err) => warn!("Error accepting network client: {}", err),
+                }
+            }
+        });
+
+        Ok(NetworkInput { tx, rx, local_addr })
+    }
+
+    pub fn udp(address: &str) -> Result<NetworkInput, InputError> {
+        let socket = UdpSocket::bind(address)?;
+        let local_addr = socket.local_addr()?;
+        let (tx, rx) = channel();
+
+        info!("Listening for events on udp:{}", local_addr);
+
+        let socket_tx = tx.clone();
+        thread::spawn(move || {
+            let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((size, peer)) => match str::from_utf8(&buffer[..size]) {
+                        Ok(datagram) => {
+                            for line in datagram.lines() {
+                                if let Err(err) = parse_and_send(line, &socket_tx) {
+                                    warn!("Bad input from {}: {}", peer, err.message);
+                                }
+                            }
+                        }
+                        Err(_) => warn!("Non-UTF8 datagram from {}", peer),
+                    },
+                    Err(err) => {
+                        error!("Error receiving on udp:{}: {}", local_addr, err);
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(NetworkInput { tx, rx, local_addr })
+    }
+}
+
+fn handle_client(stream: TcpStream, tx: Sender<Vec<BitEvent>>) -> Result<(), InputError> {
+    let peer = stream.peer_addr()?;
+    let mut writer = stream.try_clone()?;
+
+    debug!("Network client {} connected", peer);
+
+    for line in BufReader::new(stream).lines() {
+        match parse_and_send(&line?, &tx) {
+            Ok(()) => writeln!(writer, "ok")?,
+            Err(err) => {
+                warn!("Bad input from {}: {}", peer, err.message);
+                writeln!(writer, "error: {}", err.message)?;
+            }
+        }
+    }
+
+    debug!("Network client {} disconnected", peer);
+    Ok(())
+}
+
+fn parse_and_send(line: &str, tx: &Sender<Vec<BitEvent>>) -> Result<(), InputError> {
+    let trimmed = line.trim();
+
+    if trimmed.is_empty() {
+        // Empty input is not an error
+        return Ok(());
+    }
+
+    tx.send(parse_bit_events(trimmed)?)
+        .map_err(|_| InputError::from_str("Network input is shut down"))
+}
+
+impl InputHandler for NetworkInput {
+    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        receive_batches(&self.rx, None, STOPPED)
+    }
+
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        receive_batches(&self.rx, Some(timeout), STOPPED)
+    }
+
+    fn waker(&self) -> Waker {
+        batch_waker(&self.tx)
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        debug!("Setting bits {:?}", bits);
+        Ok(())
+    }
+
+    fn shutdown(self) {
+        debug!("Shutdown is NOOP on network input {}", self.local_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NetworkInput;
+    use crate::input::bitevents::BitEvent;
+    use crate::input::InputHandler;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpStream, UdpSocket};
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_tcp_clients() {
+        let mut input = NetworkInput::new("tcp:127.0.0.1:0").unwrap();
+
+        let mut first = TcpStream::connect(input.local_addr).unwrap();
+        let mut second = TcpStream::connect(input.local_addr).unwrap();
+        let mut first_reply = BufReader::new(first.try_clone().unwrap());
+        let mut reply = String::new();
+
+        first.write_all(b"main_a:1:1,main_a:2:0\r\n").unwrap();
+        first_reply.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "ok\n");
+        assert_eq!(
+            input.read_events(),
+            Ok(vec![event("main_a", 1, 1), event("main_a", 2, 0)])
+        );
+
+        second.write_all(b"main_b:7:1\n").unwrap();
+        assert_eq!(input.read_events(), Ok(vec![event("main_b", 7, 1)]));
+
+        reply.clear();
+        first.write_all(b"nonsense\n").unwrap();
+        first_reply.read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("error: "));
+    }
+
+    #[test]
+    fn test_udp() {
+        let mut input = NetworkInput::new("udp:127.0.0.1:0").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(b"main_c:3:1\nmain_c:4:0\n", input.local_addr)
+            .unwrap();
+
+        let mut events = input.read_events().unwrap();
+        while events.len() < 2 {
+            events.append(&mut input.read_events().unwrap());
+        }
+        assert_eq!(events, vec![event("main_c", 3, 1), event("main_c", 4, 0)]);
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        assert!(NetworkInput::new("http:127.0.0.1:80").is_err());
+        assert!(NetworkInput::new("127.0.0.1:80").is_err());
+    }
+}
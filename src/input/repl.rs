@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::iter::FromIterator;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::bitevents::{parse_bit_events, BitEvent};
+use super::InputError;
+use crate::bindfiles::parse_duration;
+
+// How long a press lasts when no duration is given
+const DEFAULT_PRESS: Duration = Duration::from_millis(200);
+
+// How deeply scripts can run other scripts
+const MAX_SCRIPT_DEPTH: usize = 16;
+
+const HELP: &str = "\
+Inputs are named as in the handler file, or given as <device>:<bit>
+  set <input> <value>       set an input
+  toggle <input>            flip an input from wherever it was last set
+  press <input> [duration]  press an input, releasing it after the duration (200ms by default)
+  sleep <duration>          wait, e.g. 500ms or 1.5s
+  run <script>              run the commands in a file, one per line
+  inputs                    list the named inputs
+  history                   list previous commands
+  !<n>                      repeat command n from the history
+  help                      show this
+  <device>:<bit>:<value>[,...] sets inputs directly
+Anything after a # is a comment";
+
+/// What running a command takes
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Send(Vec<BitEvent>),
+    Sleep(Duration),
+    Print(String),
+}
+
+/// Input names from the handler file and aliases. A name given to more than one input keeps the
+/// first, but can't be used to refer to either
+#[derive(Debug, Clone, Default)]
+pub struct InputNames {
+    inputs: BTreeMap<String, (String, u8)>,
+    duplicates: BTreeSet<String>,
+}
+
+impl InputNames {
+    pub fn insert(&mut self, name: &str, input: (String, u8)) {
+        match self.inputs.get(name) {
+            Some(first) if *first != input => {
+                warn!(
+                    "Input name '{}' is used for {}:{} and {}:{}, refer to them by device and bit",
+                    name, first.0, first.1, input.0, input.1
+                );
+                self.duplicates.insert(String::from(name));
+            }
+            Some(_) => {}
+            None => {
+                self.inputs.insert(String::from(name), input);
+            }
+        }
+    }
+}
+
+impl FromIterator<(String, (String, u8))> for InputNames {
+    fn from_iter<I: IntoIterator<Item = (String, (String, u8))>>(iter: I) -> InputNames {
+        let mut names = InputNames::default();
+        for (name, input) in iter {
+            names.insert(&name, input);
+        }
+        names
+    }
+}
+
+/// Turns commands typed on stdin (or read from scripts) into input events
+pub struct Repl {
+    names: InputNames,
+    // The last value set for each input, for toggling
+    levels: BTreeMap<(String, u8), u8>,
+    history: Vec<String>,
+    // Scripts being run, outermost first
+    scripts: Vec<PathBuf>,
+}
+
+impl Repl {
+    pub fn new(names: InputNames) -> Repl {
+        Repl {
+            names,
+            levels: BTreeMap::new(),
+            history: Vec::new(),
+            scripts: Vec::new(),
+        }
+    }
+
+    /// Run one line typed in, returning what it takes to carry it out
+    pub fn execute(&mut self, line: &str) -> Result<Vec<Action>, InputError> {
+        let line = strip_comment(line);
+
+        if let Some(number) = line.strip_prefix('!') {
+            let previous = number
+                .parse::<usize>()
+                .ok()
+                .and_then(|number| self.history.get(number.wrapping_sub(1)))
+                .cloned()
+                .ok_or_else(|| InputError::new(format!("No command {} in the history", number)))?;
+            return self.execute(&previous);
+        }
+
+        if !line.is_empty() {
+            self.history.push(String::from(line));
+        }
+        self.command(line)
+    }
+
+    fn command(&mut self, line: &str) -> Result<Vec<Action>, InputError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => Ok(vec![]),
+            ["help"] => Ok(vec![Action::Print(String::from(HELP))]),
+            ["inputs"] => Ok(vec![Action::Print(self.list_inputs())]),
+            ["history"] => Ok(vec![Action::Print(
+                self.history
+                    .iter()
+                    .enumerate()
+                    .map(|(index, line)| format!("{:4}  {}", index + 1, line))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )]),
+            ["set", input, value] => {
+                let input = self.resolve(input)?;
+                Ok(vec![self.send(input, value.parse()?)])
+            }
+            ["toggle", input] => {
+                let input = self.resolve(input)?;
+                let value = match self.levels.get(&input) {
+                    Some(0) | None => 1,
+                    Some(_) => 0,
+                };
+                Ok(vec![self.send(input, value)])
+            }
+            ["press", input] | ["press", input, _] => {
+                let duration = match words.get(2) {
+                    Some(duration) => parse_duration(duration)?,
+                    None => DEFAULT_PRESS,
+                };
+                let input = self.resolve(input)?;
+                Ok(vec![
+                    self.send(input.clone(), 1),
+                    Action::Sleep(duration),
+                    self.send(input, 0),
+                ])
+            }
+            ["sleep", duration] => Ok(vec![Action::Sleep(parse_duration(duration)?)]),
+            ["run", script] => self.run(script),
+            [command, ..] if command.contains(':') => {
+                let events = parse_bit_events(line)?;
+                for event in &events {
+                    self.levels
+                        .insert((event.dev_name.clone(), event.bit), event.value);
+                }
+                Ok(vec![Action::Send(events)])
+            }
+            _ => Err(InputError::new(format!(
+                "Unknown command '{}', try help",
+                line
+            ))),
+        }
+    }
+
+    fn run(&mut self, script: &str) -> Result<Vec<Action>, InputError> {
+        let path = fs::canonicalize(script)?;
+        if self.scripts.contains(&path) {
+            return Err(InputError::new(format!("{} runs itself", script)));
+        }
+        if self.scripts.len() >= MAX_SCRIPT_DEPTH {
+            return Err(InputError::new(format!(
+                "Scripts nested more than {} deep",
+                MAX_SCRIPT_DEPTH
+            )));
+        }
+
+        self.scripts.push(path);
+        let actions = self.run_lines(script);
+        self.scripts.pop();
+        actions
+    }
+
+    fn run_lines(&mut self, script: &str) -> Result<Vec<Action>, InputError> {
+        let contents = fs::read_to_string(script)?;
+        let mut actions = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            // Scripts don't go in the history, only the run command does
+            let mut line_actions = self.command(strip_comment(line)).map_err(|e| {
+                InputError::new(format!("{} line {}: {}", script, number + 1, e.message))
+            })?;
+            actions.append(&mut line_actions);
+        }
+
+        Ok(actions)
+    }
+
+    /// Find an input by name, or as <device>:<bit>
+    fn resolve(&self, input: &str) -> Result<(String, u8), InputError> {
+        if self.names.duplicates.contains(input) {
+            return Err(InputError::new(format!(
+                "Input name '{}' is used for more than one input",
+                input
+            )));
+        }
+        if let Some(input) = self.names.inputs.get(input) {
+            return Ok(input.clone());
+        }
+
+        match input.rsplit_once(':') {
+            Some((dev_name, bit)) => Ok((String::from(dev_name), bit.parse()?)),
+            None => Err(InputError::new(format!("Unknown input '{}'", input))),
+        }
+    }
+
+    fn send(&mut self, input: (String, u8), value: u8) -> Action {
+        self.levels.insert(input.clone(), value);
+        Action::Send(vec![BitEvent {
+            dev_name: input.0,
+            bit: input.1,
+            value,
+        }])
+    }
+
+    fn list_inputs(&self) -> String {
+        self.names
+            .inputs
+            .iter()
+            .map(|(name, input)| {
+                let level = match self.levels.get(input) {
+                    Some(level) => level.to_string(),
+                    None => String::from("-"),
+                };
+                format!("  {:24} {}:{}  {}", name, input.0, input.1, level)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split('#').next().unwrap_or("").trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Repl};
+    use crate::input::bitevents::BitEvent;
+    use maplit::btreemap;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    fn send(dev_name: &str, bit: u8, value: u8) -> Action {
+        Action::Send(vec![BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }])
+    }
+
+    fn repl() -> Repl {
+        Repl::new(
+            btreemap! {
+                String::from("abort") => (String::from("main_c"), 7),
+            }
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut repl = repl();
+
+        assert_eq!(repl.execute("set abort 1"), Ok(vec![send("main_c", 7, 1)]));
+        assert_eq!(repl.execute("toggle abort"), Ok(vec![send("main_c", 7, 0)]));
+        assert_eq!(
+            repl.execute("toggle main_a:2"),
+            Ok(vec![send("main_a", 2, 1)])
+        );
+        assert_eq!(
+            repl.execute("press abort 500ms  # hold it"),
+            Ok(vec![
+                send("main_c", 7, 1),
+                Action::Sleep(Duration::from_millis(500)),
+                send("main_c", 7, 0)
+            ])
+        );
+        assert_eq!(
+            repl.execute("main_a:2:0,main_b:3:1"),
+            Ok(vec![Action::Send(vec![
+                BitEvent {
+                    dev_name: String::from("main_a"),
+                    bit: 2,
+                    value: 0,
+                },
+                BitEvent {
+                    dev_name: String::from("main_b"),
+                    bit: 3,
+                    value: 1,
+                }
+            ])])
+        );
+        assert_eq!(repl.execute("   "), Ok(vec![]));
+
+        assert!(repl.execute("set nothing 1").is_err());
+        assert!(repl.execute("frobnicate").is_err());
+        assert!(repl.execute("sleep forever").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let mut repl = Repl::new(
+            vec![
+                (String::from("abort"), (String::from("main_c"), 7)),
+                (String::from("abort"), (String::from("main_c"), 7)),
+                (String::from("arm"), (String::from("main_a"), 1)),
+                (String::from("arm"), (String::from("main_b"), 1)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(repl.execute("set abort 1"), Ok(vec![send("main_c", 7, 1)]));
+        assert!(repl.execute("set arm 1").is_err());
+        assert_eq!(
+            repl.execute("set main_b:1 1"),
+            Ok(vec![send("main_b", 1, 1)])
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let mut repl = repl();
+
+        repl.execute("set abort 1").unwrap();
+        repl.execute("sleep 1s").unwrap();
+        assert_eq!(repl.execute("!1"), Ok(vec![send("main_c", 7, 1)]));
+        assert!(repl.execute("!7").is_err());
+        assert_eq!(
+            repl.execute("history"),
+            Ok(vec![Action::Print(String::from(
+                "   1  set abort 1\n   2  sleep 1s\n   3  set abort 1\n   4  history"
+            ))])
+        );
+    }
+
+    #[test]
+    fn test_script() {
+        let script = env::temp_dir().join(format!("repl-test-{}.txt", std::process::id()));
+        fs::write(
+            &script,
+            "# Abort drill\nset abort 1\nsleep 2s\n\ntoggle abort\n",
+        )
+        .unwrap();
+
+        let actions = repl().execute(&format!("run {}", script.display()));
+        fs::write(&script, "set abort 1\nfrobnicate\n").unwrap();
+        let failed = repl().execute(&format!("run {}", script.display()));
+        fs::remove_file(&script).unwrap();
+
+        assert_eq!(
+            actions,
+            Ok(vec![
+                send("main_c", 7, 1),
+                Action::Sleep(Duration::from_secs(2)),
+                send("main_c", 7, 0)
+            ])
+        );
+        assert!(failed.unwrap_err().to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn test_script_runs_itself() {
+        let dir = env::temp_dir();
+        let first = dir.join(format!("repl-loop-a-{}.txt", std::process::id()));
+        let second = dir.join(format!("repl-loop-b-{}.txt", std::process::id()));
+        fs::write(&first, format!("set abort 1\nrun {}\n", second.display())).unwrap();
+        fs::write(&second, format!("run {}\n", first.display())).unwrap();
+
+        let mut repl = repl();
+        let looped = repl.execute(&format!("run {}", first.display()));
+        fs::write(&second, "toggle abort\n").unwrap();
+        let nested = repl.execute(&format!("run {}", first.display()));
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+
+        assert!(looped.unwrap_err().to_string().contains("runs itself"));
+        // Running the same script again once the first run is over is fine
+        assert_eq!(nested, Ok(vec![send("main_c", 7, 1), send("main_c", 7, 0)]));
+    }
+}
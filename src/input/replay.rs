@@ -0,0 +1,386 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::bitevents::{parse_bit_events, BitEvent};
+use super::*;
+
+/// Wraps another handler, logging every batch of events it reads. Each line of the log holds the
+/// milliseconds since recording started and the batch in the stdin event syntax, e.g.
+/// "1520 main_a:1:1,main_a:2:0". Lines starting with '#' are comments.
+pub struct RecordingInput<T: InputHandler> {
+    inner: T,
+    log: BufWriter<File>,
+    start: Instant,
+}
+
+impl<T: InputHandler> RecordingInput<T> {
+    pub fn new(inner: T, log_file: &Path) -> Result<RecordingInput<T>, InputError> {
+        let mut log = BufWriter::new(File::create(log_file)?);
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(log, "# gemini-panel events, started {}", started.as_secs())?;
+        log.flush()?;
+
+        info!("Recording events to {:?}", log_file);
+
+        Ok(RecordingInput {
+            inner,
+            log,
+            start: Instant::now(),
+        })
+    }
+}
+
+impl<T: InputHandler> RecordingInput<T> {
+    fn record(&mut self, events: Vec<BitEvent>) -> Result<Vec<BitEvent>, InputError> {
+        if !events.is_empty() {
+            // Flush every batch so that a crash doesn't lose the interesting part
+            writeln!(self.log, "{}", format_line(self.start.elapsed(), &events))?;
+            self.log.flush()?;
+        }
+
+        Ok(events)
+    }
+}
+
+fn format_line(offset: Duration, events: &[BitEvent]) -> String {
+    let specs: Vec<String> = events
+        .iter()
+        .map(|event| format!("{}:{}:{}", event.dev_name, event.bit, event.value))
+        .collect();
+
+    format!("{} {}", offset.as_millis(), specs.join(","))
+}
+
+impl<T: InputHandler> InputHandler for RecordingInput<T> {
+    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        let events = self.inner.read_events()?;
+        self.record(events)
+    }
+
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        let events = self.inner.read_events_timeout(timeout)?;
+        self.record(events)
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        self.inner.set_output(bits)
+    }
+
+    fn output_devices(&self) -> Vec<String> {
+        self.inner.output_devices()
+    }
+
+    fn current_state(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        self.inner.current_state()
+    }
+
+    fn waker(&self) -> Waker {
+        self.inner.waker()
+    }
+
+    fn shutdown(self) {
+        self.inner.shutdown();
+    }
+}
+
+/// Plays back a log written by RecordingInput, keeping the original spacing between batches
+/// scaled by `speed` (2.0 is twice as fast)
+pub struct ReplayInput {
+    batches: Vec<(Duration, Vec<BitEvent>)>,
+    next: usize,
+    speed: f64,
+    start: Option<Instant>,
+    // Waits between batches are cut short by anything sent here
+    wake_tx: Sender<()>,
+    wake_rx: Receiver<()>,
+}
+
+// The slowest a replay can run, so that stretched offsets stay well within a Duration
+const MIN_SPEED: f64 = 0.001;
+
+impl ReplayInput {
+    /// Load a replay according to a "<log file>[@<speed>]" spec
+    pub fn new(spec: &str) -> Result<ReplayInput, InputError> {
+        let (log_file, speed) = match spec.rfind('@') {
+            Some(index) => (&spec[..index], spec[index + 1..].parse::<f64>()?),
+            None => (spec, 1.0),
+        };
+
+        if !(speed.is_finite() && speed >= MIN_SPEED) {
+            return Err(InputError::new(format!("Invalid replay speed {}", speed)));
+        }
+
+        let reader = BufReader::new(File::open(log_file)?);
+        let mut batches = Vec::new();
+
+        for line in reader.lines() {
+            if let Some(batch) = parse_line(&line?)? {
+                batches.push(batch);
+            }
+        }
+
+        info!(
+            "Replaying {} batches from {} at {}x",
+            batches.len(),
+            log_file,
+            speed
+        );
+
+        let (wake_tx, wake_rx) = channel();
+
+        Ok(ReplayInput {
+            batches,
+            next: 0,
+            speed,
+            start: None,
+            wake_tx,
+            wake_rx,
+        })
+    }
+}
+
+fn parse_line(line: &str) -> Result<Option<(Duration, Vec<BitEvent>)>, InputError> {
+    let trimmed = line.trim();
+
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(None);
+    }
+
+    match trimmed.find(' ') {
+        Some(index) => {
+            let offset = Duration::from_millis(trimmed[..index].parse()?);
+            let events = parse_bit_events(trimmed[index + 1..].trim())?;
+            Ok(Some((offset, events)))
+        }
+        None => Err(InputError::new(format!("Invalid replay line '{}'", line))),
+    }
+}
+
+impl ReplayInput {
+    /// Wait for the next batch to come due, or until `limit` or a wakeup if either comes first
+    fn next_batch(&mut self, limit: Option<Instant>) -> Vec<BitEvent> {
+        // The clock starts with the first read, not when the log was loaded
+        let start = *self.start.get_or_insert_with(Instant::now);
+
+        let remaining = self.batches.len() - self.next;
+
+        match self.batches.get(self.next) {
+            Some((offset, _)) => {
+                let due = start + offset.div_f64(self.speed);
+                let until = match limit {
+                    Some(limit) if limit < due => limit,
+                    _ => due,
+                };
+                let woken = self
+                    .wake_rx
+                    .recv_timeout(until.saturating_duration_since(Instant::now()))
+                    .is_ok();
+                if woken || until < due {
+                    return vec![];
+                }
+
+                let events = mem::take(&mut self.batches[self.next].1);
+                self.next += 1;
+                if remaining == 1 {
+                    info!("Replay complete");
+                }
+
+                events
+            }
+            None => {
+                // Nothing more will come, so only a wakeup or the limit ends the wait
+                match limit {
+                    Some(limit) => {
+                        let _ = self
+                            .wake_rx
+                            .recv_timeout(limit.saturating_duration_since(Instant::now()));
+                    }
+                    None => {
+                        let _ = self.wake_rx.recv();
+                    }
+                }
+                vec![]
+            }
+        }
+    }
+}
+
+impl InputHandler for ReplayInput {
+    fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+        Ok(self.next_batch(None))
+    }
+
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        Ok(self.next_batch(Some(Instant::now() + timeout)))
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        debug!("Replay setting bits {:?}", bits);
+        Ok(())
+    }
+
+    fn waker(&self) -> Waker {
+        let tx = self.wake_tx.clone();
+        Waker::new(move || {
+            let _ = tx.send(());
+        })
+    }
+
+    fn shutdown(self) {
+        debug!("Shutdown is NOOP on replay");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_line, parse_line, RecordingInput, ReplayInput};
+    use crate::input::bitevents::BitEvent;
+    use crate::input::{InputError, InputHandler, Waker};
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    // Hands out canned batches, one per read
+    struct CannedInput(Vec<Vec<BitEvent>>);
+
+    impl InputHandler for CannedInput {
+        fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
+            Ok(if self.0.is_empty() {
+                vec![]
+            } else {
+                self.0.remove(0)
+            })
+        }
+
+        fn read_events_timeout(&mut self, _: Duration) -> Result<Vec<BitEvent>, InputError> {
+            self.read_events()
+        }
+
+        fn set_output(&mut self, _: &[BitEvent]) -> Result<(), InputError> {
+            Ok(())
+        }
+
+        fn waker(&self) -> Waker {
+            Waker::new(|| {})
+        }
+
+        fn shutdown(self) {}
+    }
+
+    #[test]
+    fn test_line_round_trip() {
+        let events = vec![event("main_a", 1, 1), event("main_b", 15, 0)];
+        let line = format_line(Duration::from_millis(1520), &events);
+
+        assert_eq!(line, "1520 main_a:1:1,main_b:15:0");
+        assert_eq!(
+            parse_line(&line),
+            Ok(Some((Duration::from_millis(1520), events)))
+        );
+    }
+
+    #[test]
+    fn test_parse_comments_and_errors() {
+        assert_eq!(parse_line("# a comment"), Ok(None));
+        assert_eq!(parse_line("   "), Ok(None));
+        assert!(parse_line("main_a:1:1").is_err());
+        assert!(parse_line("soon main_a:1:1").is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let log_file = env::temp_dir().join(format!("gemini-replay-{}.log", std::process::id()));
+
+        let mut recorder = RecordingInput::new(
+            CannedInput(vec![
+                vec![event("main_a", 1, 1)],
+                vec![],
+                vec![event("main_a", 1, 0), event("main_c", 7, 1)],
+            ]),
+            &log_file,
+        )
+        .unwrap();
+
+        for _ in 0..3 {
+            recorder.read_events().unwrap();
+        }
+        recorder.shutdown();
+
+        let spec = format!("{}@1000", log_file.display());
+        let mut replay = ReplayInput::new(&spec).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(replay.read_events(), Ok(vec![event("main_a", 1, 1)]));
+        assert_eq!(
+            replay.read_events(),
+            Ok(vec![event("main_a", 1, 0), event("main_c", 7, 1)])
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        fs::remove_file(&log_file).unwrap();
+    }
+
+    #[test]
+    fn test_wake_replay() {
+        let log_file = env::temp_dir().join(format!("gemini-wake-{}.log", std::process::id()));
+        fs::write(&log_file, "60000 main_a:0:1\n").unwrap();
+
+        let mut replay = ReplayInput::new(&log_file.display().to_string()).unwrap();
+        fs::remove_file(&log_file).unwrap();
+
+        // Woken long before the batch is due
+        replay.waker().wake();
+        let start = Instant::now();
+        assert_eq!(replay.read_events(), Ok(vec![]));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_invalid_speed() {
+        assert!(ReplayInput::new("missing.log@0").is_err());
+        assert!(ReplayInput::new("missing.log@fast").is_err());
+        // Turned down before the log is even opened
+        for spec in &["missing.log@nan", "missing.log@inf", "missing.log@1e-300"] {
+            assert!(
+                matches!(ReplayInput::new(spec), Err(e) if e.to_string().contains("speed")),
+                "{}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_replay_speed() {
+        let log_file = env::temp_dir().join(format!("gemini-speed-{}.log", std::process::id()));
+        fs::write(&log_file, "# test\n0 main_a:0:1\n1000 main_a:0:0\n").unwrap();
+
+        let spec = format!("{}@4", log_file.display());
+        let mut replay = ReplayInput::new(&spec).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(replay.read_events(), Ok(vec![event("main_a", 0, 1)]));
+        assert_eq!(replay.read_events(), Ok(vec![event("main_a", 0, 0)]));
+
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250));
+        // Well short of the recorded second, however busy the machine is
+        assert!(elapsed < Duration::from_secs(1));
+
+        fs::remove_file(&log_file).unwrap();
+    }
+}
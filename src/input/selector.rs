@@ -0,0 +1,301 @@
+use serde::Deserialize;
+
+use std::time::{Duration, Instant};
+
+use super::bitevents::BitEvent;
+
+/// A rotary selector wired across several pins of one device. Instead of per-pin events it
+/// reports "<name>:<position>:1" whenever it settles on a new position, so handlers can bind each
+/// position as a bit of a virtual device named after the selector. The position it left, if any,
+/// is reported as "<name>:<position>:0" just before, so only one position is ever on.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct SelectorConfig {
+    pub name: String,
+    // Pins in order, least significant (or first position) first
+    pub pins: Vec<u8>,
+    #[serde(default)]
+    pub encoding: Encoding,
+    // How long a new position must hold before it is reported
+    #[serde(default)]
+    pub settle_ms: u64,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    // One pin per position
+    #[default]
+    OneHot,
+    Binary,
+    Gray,
+}
+
+/// Decode the selector's pins (pins[0] in bit 0) into a position. One-hot codes with no pin or
+/// several pins active are in between positions.
+fn decode(encoding: Encoding, code: u16) -> Option<u8> {
+    match encoding {
+        Encoding::OneHot if code.count_ones() == 1 => Some(code.trailing_zeros() as u8),
+        Encoding::OneHot => None,
+        Encoding::Binary => Some(code as u8),
+        Encoding::Gray => {
+            let mut binary = code;
+            let mut shift = code >> 1;
+            while shift != 0 {
+                binary ^= shift;
+                shift >>= 1;
+            }
+            Some(binary as u8)
+        }
+    }
+}
+
+pub struct Selector {
+    config: SelectorConfig,
+    // Current pin levels, packed in selector pin order
+    code: u16,
+    position: Option<u8>,
+    // A new position that has not yet held for the settle time
+    pending: Option<(u8, Instant)>,
+}
+
+impl Selector {
+    pub fn new(config: SelectorConfig) -> Selector {
+        Selector {
+            config,
+            code: 0,
+            position: None,
+            pending: None,
+        }
+    }
+
+    /// Take the position from the given device pin state without reporting it
+    pub fn reset(&mut self, pins: u16) {
+        self.code = self
+            .config
+            .pins
+            .iter()
+            .enumerate()
+            .fold(0, |code, (index, &pin)| {
+                code | (pins >> pin & 0x01) << index
+            });
+        self.position = decode(self.config.encoding, self.code);
+        self.pending = None;
+    }
+
+    pub fn owns(&self, bit: u8) -> bool {
+        self.pin_index(bit).is_some()
+    }
+
+    /// The position event for where the selector is now, if it is at a position at all
+    pub fn current_state(&self) -> Option<BitEvent> {
+        self.position.map(|position| BitEvent {
+            dev_name: self.config.name.clone(),
+            bit: position,
+            value: 1,
+        })
+    }
+
+    fn pin_index(&self, bit: u8) -> Option<usize> {
+        self.config.pins.iter().position(|&pin| pin == bit)
+    }
+
+    fn settle_time(&self) -> Duration {
+        Duration::from_millis(self.config.settle_ms)
+    }
+
+    fn update(&mut self, index: usize, value: u8) {
+        if value == 0 {
+            self.code &= !(1 << index);
+        } else {
+            self.code |= 1 << index;
+        }
+    }
+
+    /// Work out whether the selector has settled on a new position as of `now`, returning the
+    /// events for leaving the old position and arriving at the new one
+    fn settle(&mut self, now: Instant) -> Vec<BitEvent> {
+        match decode(self.config.encoding, self.code) {
+            decoded if decoded == self.position => self.pending = None,
+            Some(position) => match self.pending {
+                Some((pending, _)) if pending == position => {}
+                _ => self.pending = Some((position, now)),
+            },
+            // In between positions, wait for it to land somewhere
+            None => self.pending = None,
+        }
+
+        match self.pending {
+            Some((position, since)) if now.duration_since(since) >= self.settle_time() => {
+                debug!("Selector {} moved to {}", self.config.name, position);
+                let left = self.position.replace(position);
+                self.pending = None;
+
+                left.map(|left| (left, 0))
+                    .into_iter()
+                    .chain(Some((position, 1)))
+                    .map(|(bit, value)| BitEvent {
+                        dev_name: self.config.name.clone(),
+                        bit,
+                        value,
+                    })
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, since)| since + self.settle_time())
+    }
+}
+
+/// Route the pin events that belong to selectors into them, returning the remaining pin events
+/// followed by any selector position changes
+pub fn decode_selectors(
+    selectors: &mut [Selector],
+    events: Vec<BitEvent>,
+    now: Instant,
+) -> Vec<BitEvent> {
+    if selectors.is_empty() {
+        return events;
+    }
+
+    let mut result = Vec::with_capacity(events.len());
+
+    for event in events {
+        let owner = selectors
+            .iter_mut()
+            .find_map(|selector| selector.pin_index(event.bit).map(|index| (selector, index)));
+
+        match owner {
+            Some((selector, index)) => selector.update(index, event.value),
+            None => result.push(event),
+        }
+    }
+
+    result.extend(
+        selectors
+            .iter_mut()
+            .flat_map(|selector| selector.settle(now)),
+    );
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_selectors, Encoding, Selector, SelectorConfig};
+    use crate::input::bitevents::BitEvent;
+    use std::time::{Duration, Instant};
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    fn selector(encoding: Encoding, settle_ms: u64) -> Selector {
+        Selector::new(SelectorConfig {
+            name: String::from("mode"),
+            pins: vec![4, 5, 6],
+            encoding,
+            settle_ms,
+        })
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(Encoding::OneHot, 0b100), Some(2));
+        assert_eq!(decode(Encoding::OneHot, 0b000), None);
+        assert_eq!(decode(Encoding::OneHot, 0b110), None);
+        assert_eq!(decode(Encoding::Binary, 0b110), Some(6));
+        // Gray code for 0..8
+        let gray = [0b000, 0b001, 0b011, 0b010, 0b110, 0b111, 0b101, 0b100];
+        for (position, &code) in gray.iter().enumerate() {
+            assert_eq!(decode(Encoding::Gray, code), Some(position as u8));
+        }
+    }
+
+    #[test]
+    fn test_position_change() {
+        let mut selectors = vec![selector(Encoding::OneHot, 0)];
+        selectors[0].reset(0b0001_0000);
+        let now = Instant::now();
+
+        // Pin events for the selector are replaced by one position event
+        assert_eq!(
+            decode_selectors(
+                &mut selectors,
+                vec![
+                    event("main_a", 1, 1),
+                    event("main_a", 4, 0),
+                    event("main_a", 5, 1)
+                ],
+                now
+            ),
+            vec![
+                event("main_a", 1, 1),
+                event("mode", 0, 0),
+                event("mode", 1, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_glitch_suppression() {
+        let mut selectors = vec![selector(Encoding::OneHot, 50)];
+        selectors[0].reset(0b0001_0000);
+        let start = Instant::now();
+
+        // Break before make: no pins active between detents
+        assert_eq!(
+            decode_selectors(&mut selectors, vec![event("main_a", 4, 0)], start),
+            vec![]
+        );
+        assert_eq!(selectors[0].next_deadline(), None);
+
+        // Brushing past position 1 on the way to position 2
+        let brush = start + Duration::from_millis(10);
+        decode_selectors(&mut selectors, vec![event("main_a", 5, 1)], brush);
+        assert_eq!(
+            selectors[0].next_deadline(),
+            Some(brush + Duration::from_millis(50))
+        );
+        decode_selectors(
+            &mut selectors,
+            vec![event("main_a", 5, 0)],
+            start + Duration::from_millis(20),
+        );
+
+        let land = start + Duration::from_millis(30);
+        assert_eq!(
+            decode_selectors(&mut selectors, vec![event("main_a", 6, 1)], land),
+            vec![]
+        );
+        assert_eq!(
+            decode_selectors(&mut selectors, vec![], land + Duration::from_millis(50)),
+            vec![event("mode", 0, 0), event("mode", 2, 1)]
+        );
+    }
+
+    #[test]
+    fn test_return_to_current_position() {
+        let mut selectors = vec![selector(Encoding::Binary, 50)];
+        selectors[0].reset(0b0001_0000);
+        let start = Instant::now();
+
+        decode_selectors(&mut selectors, vec![event("main_a", 5, 1)], start);
+        decode_selectors(
+            &mut selectors,
+            vec![event("main_a", 5, 0)],
+            start + Duration::from_millis(10),
+        );
+
+        assert_eq!(
+            decode_selectors(&mut selectors, vec![], start + Duration::from_millis(100)),
+            vec![]
+        );
+    }
+}
@@ -1,110 +1,141 @@
-use std::io;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, SendError, Sender, TryRecvError};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender};
 use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use super::bitevents::BitEvent;
+use super::repl::{Action, InputNames, Repl};
 use super::*;
 
+/// Reads commands from stdin, with line editing and history when it's a terminal. Type help for
+/// the list of commands
 pub struct StdinInput {
     poller: JoinHandle<()>,
-    rx: Receiver<String>,
+    // Errors are only sent once stdin has been closed for good
+    tx: Sender<Result<Vec<BitEvent>, InputError>>,
+    rx: Receiver<Result<Vec<BitEvent>, InputError>>,
     poll_condition: Arc<AtomicBool>,
 }
 
-impl From<SendError<String>> for InputError {
-    fn from(err: SendError<String>) -> InputError {
-        InputError {
-            message: format!("Unable to send '{}' on channel", err.0),
-        }
+impl From<SendError<Result<Vec<BitEvent>, InputError>>> for InputError {
+    fn from(err: SendError<Result<Vec<BitEvent>, InputError>>) -> InputError {
+        InputError::new(format!("Unable to send {:?} on channel", err.0))
     }
 }
 
-const DEFAULT_INPUT_BUFFER_SIZE: usize = 1024;
+const PROMPT: &str = "panel> ";
 
 impl StdinInput {
-    pub fn new() -> StdinInput {
+    /// Inputs can be referred to by the given names, as well as by device and bit
+    pub fn new(names: InputNames) -> StdinInput {
         // Set up a thread to poll for input on stdin, and a channel to use for transferring that input
-        let (mut tx, rx) = channel();
+        let (tx, rx) = channel();
+        let mut reader_tx = tx.clone();
 
         let poll_guard = Arc::new(AtomicBool::new(true));
 
         let poll_condition = poll_guard.clone();
 
         let poller = thread::spawn(move || {
-            let mut buffer = String::with_capacity(DEFAULT_INPUT_BUFFER_SIZE);
+            let mut editor = Editor::<()>::new();
+            let mut repl = Repl::new(names);
             let should_run = poll_guard.clone();
             while should_run.load(Ordering::Relaxed) {
-                buffer.clear();
-                read_and_send(&mut buffer, &mut tx).unwrap_or_else(|err| {
-                    warn!("Error polling stdin: {}", err.message);
-                });
+                match read_and_send(&mut editor, &mut repl, &mut reader_tx) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        // Keep the channel open so the handler just sees no more input, rather
+                        // than reading EOF over and over
+                        info!("Stdin closed");
+                        while should_run.load(Ordering::Relaxed) {
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                    }
+                    Err(err) if err.is_closed() => {
+                        // Nothing more to read, and the main loop needs to hear why
+                        let _ = reader_tx.send(Err(err));
+                        return;
+                    }
+                    Err(err) => {
+                        println!("{}", err.message);
+                    }
+                }
             }
         });
 
         // Noop
         StdinInput {
             poller,
+            tx,
             rx,
             poll_condition,
         }
     }
 }
 
-/// A utility method to simplify error type unification
-fn read_and_send(mut buffer: &mut String, tx: &mut Sender<String>) -> Result<(), InputError> {
-    io::stdin().read_line(&mut buffer)?;
+/// Read and run a command, sending on any input events as they happen. Returns false once stdin
+/// is closed
+fn read_and_send(
+    editor: &mut Editor<()>,
+    repl: &mut Repl,
+    tx: &mut Sender<Result<Vec<BitEvent>, InputError>>,
+) -> Result<bool, InputError> {
+    let line = match editor.readline(PROMPT) {
+        Ok(line) => line,
+        Err(ReadlineError::Eof) => return Ok(false),
+        Err(ReadlineError::Interrupted) => {
+            // The terminal is in raw mode, so Ctrl-C never became a signal. Stop as it would have
+            return Err(InputError::closed("Interrupted"));
+        }
+        Err(err) => return Err(InputError::new(format!("Error reading stdin: {}", err))),
+    };
 
-    let trimmed = buffer.trim();
+    editor.add_history_entry(line.as_str());
 
-    if !trimmed.is_empty() {
-        Ok(tx.send(trimmed.clone().to_string())?)
-    } else {
-        // Empty input is not an error
-        Ok(())
+    for action in repl.execute(&line)? {
+        match action {
+            Action::Send(events) => tx.send(Ok(events))?,
+            Action::Sleep(duration) => thread::sleep(duration),
+            Action::Print(text) => println!("{}", text),
+        }
     }
+
+    Ok(true)
 }
 
 impl InputHandler for StdinInput {
     fn read_events(&mut self) -> Result<Vec<BitEvent>, InputError> {
-        match self.rx.try_recv() {
-            Ok(input) => {
-                // The poller thread guarantees that input is non-empty
-                input
-                    .split(',')
-                    .map(|s| {
-                        debug!("Parsing '{}'", s);
-                        let parts = s.split(':').collect::<Vec<_>>();
-                        if parts.len() == 3 {
-                            Ok(BitEvent {
-                                dev_name: String::from(parts[0]),
-                                bit: parts[1].parse()?,
-                                value: parts[2].parse()?,
-                            })
-                        } else {
-                            Err(InputError {
-                                message: format!("Invalid input spec: '{}'", s),
-                            })
-                        }
-                    })
-                    .collect()
-            }
+        match self.rx.recv() {
+            Ok(events) => events,
 
-            Err(TryRecvError::Empty) => {
-                // Noop, empty input is fine
-                Ok(vec![])
-            }
+            Err(_) => Err(InputError::from_str("Stdin channel is disconnected")),
+        }
+    }
 
-            Err(TryRecvError::Disconnected) => {
-                panic!("Stdin channel is disconnected! Hard stop.");
+    fn read_events_timeout(&mut self, timeout: Duration) -> Result<Vec<BitEvent>, InputError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(events) => events,
+            Err(RecvTimeoutError::Timeout) => Ok(vec![]),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(InputError::from_str("Stdin channel is disconnected"))
             }
         }
     }
 
-    fn set_output(&mut self, dev_index: usize, bits: &[BitEvent]) -> Result<(), InputError> {
-        println!("Setting bits {:?} for dev {}", bits, dev_index);
+    fn waker(&self) -> Waker {
+        let tx = self.tx.clone();
+        Waker::new(move || {
+            let _ = tx.send(Ok(Vec::new()));
+        })
+    }
+
+    fn set_output(&mut self, bits: &[BitEvent]) -> Result<(), InputError> {
+        println!("Setting bits {:?}", bits);
         Ok(())
     }
 
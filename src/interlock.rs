@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::aliases::Aliases;
+use crate::input::InputError;
+
+/// The current value of every input the simulator has heard about
+pub type InputState = BTreeMap<(String, u8), u8>;
+
+/// Inputs that have to be at given values for a handler to fire, such as "retro_arm=1". Several
+/// are joined with &, and inputs are given by alias or as <device>:<bit>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    required: Vec<((String, u8), u8)>,
+}
+
+impl Condition {
+    pub fn parse(condition: &str, aliases: &Aliases) -> Result<Condition, InputError> {
+        let required = condition
+            .split('&')
+            .map(|term| match term.split_once('=') {
+                Some((input, value)) => Ok((aliases.resolve(input.trim())?, value.trim().parse()?)),
+                None => Err(InputError::new(format!(
+                    "Invalid condition '{}', expected <input>=<value>",
+                    term.trim()
+                ))),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Condition { required })
+    }
+
+    /// Whether every input is where it needs to be. Inputs that haven't been heard from yet are
+    /// taken to be off
+    pub fn holds(&self, inputs: &InputState) -> bool {
+        self.required
+            .iter()
+            .all(|(input, value)| inputs.get(input).copied().unwrap_or(0) == *value)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms: Vec<String> = self
+            .required
+            .iter()
+            .map(|((dev_name, bit), value)| format!("{}:{}={}", dev_name, bit, value))
+            .collect();
+        write!(f, "{}", terms.join(" & "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condition, InputState};
+    use crate::aliases::Aliases;
+    use maplit::btreemap;
+
+    #[test]
+    fn test_condition() {
+        let aliases = Aliases::parse("retro_arm = main_b:3").unwrap();
+        let condition = Condition::parse("retro_arm=1 & main_a:5 = 0", &aliases).unwrap();
+
+        let mut inputs: InputState = btreemap! {
+            (String::from("main_b"), 3) => 1,
+        };
+        assert!(condition.holds(&inputs));
+
+        inputs.insert((String::from("main_a"), 5), 1);
+        assert!(!condition.holds(&inputs));
+        assert_eq!(condition.to_string(), "main_b:3=1 & main_a:5=0");
+
+        assert!(Condition::parse("retro_arm", &aliases).is_err());
+        assert!(Condition::parse("nothing=1", &aliases).is_err());
+    }
+}
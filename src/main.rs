@@ -1,3 +1,4 @@
+extern crate gpio_cdev;
 extern crate i2cdev;
 
 extern crate serde;
@@ -7,6 +8,8 @@ extern crate serde_yaml;
 extern crate log;
 extern crate env_logger;
 
+extern crate evdev;
+
 extern crate music;
 
 use std::collections::BTreeSet;
@@ -14,9 +17,17 @@ use std::env;
 use std::io::Read;
 use std::process;
 use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
 
+mod aliases;
+mod animation;
 mod bindfiles;
+mod display;
+mod gesture;
 mod input;
+mod interlock;
+mod mission;
 mod simulation;
 
 use input::bitevents::BitEvent;
@@ -29,11 +40,38 @@ enum Music {
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2 || args.len() > 3 {
-        eprintln!("Usage: {} <device config> <event handler file>", args[0]);
-        process::exit(-1);
+    if args.len() >= 3 && args[1] == "bench" {
+        env_logger::init();
+        bench(&args[2], args.get(3));
+        return;
     }
 
+    let options = if args.len() >= 3 {
+        parse_options(&args[3..])
+    } else {
+        None
+    };
+
+    let options = match options {
+        Some(options) => options,
+        None => {
+            eprintln!(
+                "Usage: {} <input> <event handler file> [--record <event log>] [--sync] [--aliases <alias file>] [--mission <mission file>]",
+                args[0]
+            );
+            eprintln!("  <input> is one of: <device config>, stdin, evdev:<key map>, tcp:<address>, udp:<address>, replay:<event log>[@<speed>]");
+            eprintln!("    or several of them separated by commas, to run them all at once");
+            eprintln!("  --sync tells the handlers where every input is at startup");
+            eprintln!(
+                "  --aliases names inputs and outputs, one \"<name> = <device>:<bit>\" per line"
+            );
+            eprintln!("  --mission runs the phases of a mission declared in YAML");
+            eprintln!("   or: {} bench <device config> [cycles]", args[0]);
+            eprintln!("  to time reading every expander in each read mode");
+            process::exit(-1);
+        }
+    };
+
     env_logger::init();
 
     // Set up a channel for simulation feedback
@@ -48,64 +86,247 @@ fn main() {
         info!("Starting music...");
         music::play_music(&Music::Background, music::Repeat::Forever);
 
-        let handlers = load_handlers(&args[2]).expect("Failed to load handlers");
+        let aliases = match options.aliases {
+            Some(ref alias_file) => {
+                aliases::Aliases::load(alias_file).expect("Failed to load aliases")
+            }
+            None => aliases::Aliases::default(),
+        };
+        let (handlers, names) = load_handlers(&args[2], &aliases).expect("Failed to load handlers");
+        let mission = options.mission.as_ref().map(|mission_file| {
+            load_mission(mission_file, &aliases).expect("Failed to load mission")
+        });
 
-        let sim = init_simulator(&tx, handlers);
+        let sim = init_simulator(&tx, handlers, aliases, mission);
 
         info!("Configuring devices...");
 
-        if args[1].to_lowercase() == "stdin" {
+        let specs: Vec<&str> = args[1].split(',').collect();
+
+        if specs.len() > 1 {
+            debug!("Read composite");
+            let mut composite = input::composite::CompositeInput::new();
+            for spec in specs {
+                add_input(&mut composite, spec, &names);
+            }
+            run(composite, &options, rx, sim);
+        } else if args[1].to_lowercase() == "stdin" {
             debug!("Read Stdin");
-            main_loop(&mut input::stdin::StdinInput::new(), rx, sim);
+            run(input::stdin::StdinInput::new(names), &options, rx, sim);
+        } else if let Some(key_map) = args[1].strip_prefix("evdev:") {
+            debug!("Read input devices");
+            run(
+                input::event_device::EventDeviceInput::new(Path::new(key_map))
+                    .expect("Could not open input devices"),
+                &options,
+                rx,
+                sim,
+            );
+        } else if args[1].starts_with("tcp:") || args[1].starts_with("udp:") {
+            debug!("Read network");
+            run(
+                input::network::NetworkInput::new(&args[1])
+                    .expect("Could not listen for network input"),
+                &options,
+                rx,
+                sim,
+            );
+        } else if let Some(replay) = args[1].strip_prefix("replay:") {
+            debug!("Read replay");
+            run(
+                input::replay::ReplayInput::new(replay).expect("Could not load replay"),
+                &options,
+                rx,
+                sim,
+            );
         } else {
             debug!("Read MCP23017");
+            run(open_panel(&args[1]), &options, rx, sim);
+        }
 
-            // Read in device config
-            let mut dev_config_contents = String::new();
-            File::open(args[1].clone())
-                .unwrap()
-                .read_to_string(&mut dev_config_contents)
-                .unwrap();
+        info!("Sound complete");
+    });
+}
 
-            let devices: Vec<input::mcp23017::config::DeviceConfig> =
-                serde_yaml::from_str(&dev_config_contents).unwrap();
+fn read_device_config(config_file: &str) -> Vec<input::mcp23017::config::PanelDeviceConfig> {
+    let mut dev_config_contents = String::new();
+    File::open(config_file)
+        .unwrap()
+        .read_to_string(&mut dev_config_contents)
+        .unwrap();
 
-            println!("Read devices: {:?}", devices);
+    serde_yaml::from_str(&dev_config_contents).unwrap()
+}
 
-            main_loop(
-                &mut input::mcp23017::PanelInputHandler::new(&devices)
-                    .expect("Could not init MCP23017s"),
-                rx,
-                sim,
-            );
+fn open_panel(config_file: &str) -> input::mcp23017::PanelInputHandler {
+    let devices = read_device_config(config_file);
+
+    println!("Read devices: {:?}", devices);
+
+    input::mcp23017::PanelInputHandler::new(&devices).expect("Could not init MCP23017s")
+}
+
+/// Print how long it takes to read all of the configured expanders in each read mode
+fn bench(config_file: &str, cycles: Option<&String>) {
+    let cycles = cycles.map_or(1000, |cycles| cycles.parse().expect("Invalid cycle count"));
+    let devices = read_device_config(config_file);
+
+    println!("Average over {} cycles:", cycles);
+    for (read_mode, result) in input::mcp23017::benchmark::benchmark(&devices, cycles) {
+        match result {
+            Ok(time) => println!(
+                "  {:6} poll {:>10?}  interrupt {:>10?}",
+                format!("{:?}", read_mode),
+                time.poll,
+                time.interrupt
+            ),
+            Err(e) => println!("  {:6} {}", format!("{:?}", read_mode), e),
         }
+    }
+}
 
-        info!("Sound complete");
-    });
+/// Add one of several inputs given on the command line to a composite input
+fn add_input(composite: &mut input::composite::CompositeInput, spec: &str, names: &InputNames) {
+    if spec.to_lowercase() == "stdin" {
+        composite.add(spec, input::stdin::StdinInput::new(names.clone()));
+    } else if let Some(key_map) = spec.strip_prefix("evdev:") {
+        composite.add(
+            spec,
+            input::event_device::EventDeviceInput::new(Path::new(key_map))
+                .expect("Could not open input devices"),
+        );
+    } else if spec.starts_with("tcp:") || spec.starts_with("udp:") {
+        composite.add(
+            spec,
+            input::network::NetworkInput::new(spec).expect("Could not listen for network input"),
+        );
+    } else if let Some(replay) = spec.strip_prefix("replay:") {
+        composite.add(
+            spec,
+            input::replay::ReplayInput::new(replay).expect("Could not load replay"),
+        );
+    } else {
+        composite.add(spec, open_panel(spec));
+    }
+}
+
+struct Options {
+    // Where to record input events, if anywhere
+    record: Option<PathBuf>,
+    // Whether to tell the handlers the state of every input at startup
+    sync: bool,
+    // Where input and output names are declared, if anywhere
+    aliases: Option<String>,
+    // The mission to run, if any
+    mission: Option<String>,
+}
+
+/// Parse the options following the input and handler file, or None if they don't make sense
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        record: None,
+        sync: false,
+        aliases: None,
+        mission: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => options.record = Some(PathBuf::from(args.next()?)),
+            "--sync" => options.sync = true,
+            "--aliases" => options.aliases = Some(args.next()?.clone()),
+            "--mission" => options.mission = Some(args.next()?.clone()),
+            _ => return None,
+        }
+    }
+
+    Some(options)
+}
+
+/// Run the main loop on the given input, optionally recording everything it reads
+fn run<T: input::InputHandler>(
+    mut input: T,
+    options: &Options,
+    rx: mpsc::Receiver<BitEvent>,
+    sim: simulation::Simulator,
+) {
+    match options.record {
+        Some(ref log_file) => {
+            let mut input = input::replay::RecordingInput::new(input, log_file)
+                .expect("Could not open event log");
+            main_loop(&mut input, options.sync, rx, sim);
+            input.shutdown();
+        }
+        None => {
+            main_loop(&mut input, options.sync, rx, sim);
+            input.shutdown();
+        }
+    }
 }
 
 fn main_loop<T: input::InputHandler>(
     input: &mut T,
+    sync: bool,
     rx: mpsc::Receiver<BitEvent>,
     sim: simulation::Simulator,
 ) {
-    loop {
-        // fetch any pending handler feedback events
-        let feedback_events: Vec<BitEvent> = rx.try_iter().collect();
+    if sync {
+        match input.current_state() {
+            Ok(events) => {
+                info!("Initial state {:?}", events);
+                sim.sync(&events);
+            }
+            Err(e) => {
+                error!("Error reading initial state: {}", e);
+            }
+        }
+    }
 
-        if !feedback_events.is_empty() {
-            input.set_output(3, &feedback_events).unwrap_or_else(|err| {
-                warn!("Error setting outputs {:?}: {}", feedback_events, err);
-            });
+    // Feedback sent from other threads wakes up the wait on inputs, so it goes out straight away.
+    // Handlers run on this thread, so anything they send is already waiting when the loop comes
+    // round
+    let (feedback_tx, feedback) = mpsc::channel();
+    let waker = input.waker();
+    thread::spawn(move || {
+        for event in rx {
+            if feedback_tx.send(event).is_err() {
+                return;
+            }
+            waker.wake();
         }
+    });
+
+    loop {
+        let now = Instant::now();
+        sim.expire(now);
+        let mut outputs: Vec<BitEvent> = feedback.try_iter().collect();
+        outputs.append(&mut sim.animate(now));
+        set_outputs(input, &sim.aliases().resolve_outputs(outputs));
+
+        // Sleep until something happens, or in time for the next animation step or gesture
+        let read = match sim.next_deadline() {
+            Some(deadline) => input.read_events_timeout(deadline.saturating_duration_since(now)),
+            None => input.read_events(),
+        };
 
-        match input.read_events() {
+        match read {
             Ok(ref events) if !events.is_empty() => {
-                info!("Read {:?}", events);
-                sim.process(&events);
+                info!(
+                    "Read {:?}",
+                    events
+                        .iter()
+                        .map(|event| sim.aliases().describe(event))
+                        .collect::<Vec<_>>()
+                );
+                sim.process(events);
             }
             Ok(_) => {
-                // Noop on empty input
+                // Timed out, or woken up for feedback
+            }
+            Err(e) if e.is_closed() => {
+                info!("Input closed, stopping: {}", e);
+                return;
             }
             Err(e) => {
                 error!("Error reading events: {}", e);
@@ -114,15 +335,47 @@ fn main_loop<T: input::InputHandler>(
     }
 }
 
+/// Send any pending handler feedback and animation steps to the outputs
+fn set_outputs<T: input::InputHandler>(input: &mut T, outputs: &[BitEvent]) {
+    if !outputs.is_empty() {
+        input.set_output(outputs).unwrap_or_else(|err| {
+            warn!("Error setting outputs {:?}: {}", outputs, err);
+        });
+    }
+}
+
 // Globals for now, need to encapsulate state later
 
-fn init_simulator(sender: &mpsc::Sender<BitEvent>, handlers: HandlerMap) -> simulation::Simulator {
+fn init_simulator(
+    sender: &mpsc::Sender<BitEvent>,
+    handlers: HandlerMap,
+    aliases: aliases::Aliases,
+    mission: Option<mission::Mission>,
+) -> simulation::Simulator {
     use simulation::*;
 
-    Simulator::new(handlers, &sender)
+    Simulator::new(handlers, aliases, mission, sender).expect("Invalid gesture binding")
+}
+
+/// Load a mission and bind its sounds, relative to the mission file
+fn load_mission(
+    filename: &str,
+    aliases: &aliases::Aliases,
+) -> Result<mission::Mission, InputError> {
+    let mission = mission::Mission::load(filename, aliases)?;
+    let base_dir = Path::new(filename)
+        .parent()
+        .unwrap_or_else(|| Path::new("."));
+
+    for sound in mission.sounds() {
+        bind_soundfile(sound, base_dir)?;
+    }
+
+    Ok(mission)
 }
 
-use input::InputError;
+use input::repl::InputNames;
+use input::{InputError, InputHandler};
 use simulation::HandlerMap;
 use std::collections::BTreeMap;
 use std::fs::File;
@@ -132,9 +385,17 @@ const DEFAULT_NAME: &str = "default";
 
 // Format for each line is "<device name>, <input index>, <name>, <on sound filename>, <off sound filename>"
 // Filenames can have an optional float suffix (0-1] to specify volume
-fn load_handlers(filename: &str) -> Result<HandlerMap, InputError> {
-    use std::str::FromStr;
-
+// The device name can also be a gesture, such as "long:main_a" (see gesture::Gesture), or an alias
+// in which case the input index can be left empty
+// Three more columns are optional, "<condition>, <alternate sound filename>, <animation>". The
+// handler only fires while the condition holds (see interlock::Condition), otherwise the alternate
+// sound plays. The animation runs on outputs while the input is on (see animation::Animation::parse)
+// The names of inputs that can be set are collected too, along with the aliases, to refer to them
+// by on stdin
+fn load_handlers(
+    filename: &str,
+    aliases: &aliases::Aliases,
+) -> Result<(HandlerMap, InputNames), InputError> {
     let file_path = Path::new(filename);
 
     let mut loaded_sounds: BTreeSet<&String> = BTreeSet::new();
@@ -148,6 +409,10 @@ fn load_handlers(filename: &str) -> Result<HandlerMap, InputError> {
     };
 
     let mut result: HandlerMap = BTreeMap::new();
+    let mut names: InputNames = aliases
+        .iter()
+        .map(|(name, input)| (name.clone(), input.clone()))
+        .collect();
 
     for line_result in reader.lines() {
         let line = line_result.unwrap();
@@ -156,19 +421,30 @@ fn load_handlers(filename: &str) -> Result<HandlerMap, InputError> {
         let key: (String, u8) = if parts[0] == DEFAULT_NAME {
             debug!("Default handler: {}", line);
             simulation::default_handler_event()
+        } else if let Some(input) = aliases.get(parts[0].trim()) {
+            input.clone()
         } else {
-            (String::from(parts[0]), u8::from_str(parts[1]).unwrap())
+            (
+                String::from(parts[0]),
+                input_index(parts[0], parts[1], aliases)?,
+            )
         };
 
         if result.contains_key(&key) {
             warn!("Redefining input: {:?}", parts);
         }
 
+        // Only inputs that can actually be set
+        let dev_name = parts[0].trim();
+        if dev_name != DEFAULT_NAME && gesture::Gesture::parse(dev_name, 0, aliases).is_none() {
+            names.insert(parts[2].trim(), key.clone());
+        }
+
         let on_file = bindfiles::parse_sound_filename(parts[3].trim()).unwrap();
 
         if let Some((on_filename, _)) = on_file {
             if !loaded_sounds.contains(on_filename) {
-                bind_soundfile(&on_filename, &base_dir).unwrap();
+                bind_soundfile(on_filename, base_dir).unwrap();
                 loaded_sounds.insert(on_filename);
             }
         }
@@ -177,22 +453,73 @@ fn load_handlers(filename: &str) -> Result<HandlerMap, InputError> {
 
         if let Some((off_filename, _)) = off_file {
             if !loaded_sounds.contains(off_filename) {
-                bind_soundfile(&off_filename, &base_dir).unwrap();
+                bind_soundfile(off_filename, base_dir).unwrap();
                 loaded_sounds.insert(off_filename);
             }
         }
 
-        if let Some(handler) =
-            bindfiles::create_handler(to_static(parts[1].trim()), on_file, off_file)
-        {
+        let alternate_file = match parts.get(6) {
+            Some(alternate) => bindfiles::parse_sound_filename(alternate.trim())?,
+            None => None,
+        };
+
+        if let Some((alternate_filename, _)) = alternate_file {
+            if !loaded_sounds.contains(alternate_filename) {
+                bind_soundfile(alternate_filename, base_dir)?;
+                loaded_sounds.insert(alternate_filename);
+            }
+        }
+
+        let animation = match parts.get(7).map(|animation| animation.trim()) {
+            Some(animation) if !animation.is_empty() => {
+                Some(animation::Animation::parse(animation, aliases)?)
+            }
+            _ => None,
+        };
+
+        let name = to_static(parts[2].trim());
+        let handler = bindfiles::create_handler(name, on_file, off_file, animation);
+
+        let handler = match parts.get(5).map(|condition| condition.trim()) {
+            Some(condition) if !condition.is_empty() => {
+                let condition = interlock::Condition::parse(condition, aliases)?;
+                let alternate_name = to_static(&format!("{} (interlocked)", name));
+                let alternate =
+                    bindfiles::create_handler(alternate_name, alternate_file, None, None);
+                let handler = handler
+                    .unwrap_or_else(|| simulation::EventHandler::new(name, Box::new(|_, _, _| {})));
+                Some(handler.interlocked(condition, alternate))
+            }
+            _ => handler,
+        };
+
+        if let Some(handler) = handler {
             result.insert(key, handler);
         }
     }
 
-    Ok(result)
+    Ok((result, names))
+}
+
+// The input index column, which can only be left empty for aliases and gestures
+fn input_index(dev_name: &str, column: &str, aliases: &aliases::Aliases) -> Result<u8, InputError> {
+    let dev_name = dev_name.trim();
+
+    match column.trim() {
+        "" if aliases.get(dev_name).is_some()
+            || gesture::Gesture::parse(dev_name, 0, aliases).is_some() =>
+        {
+            Ok(0)
+        }
+        "" => Err(InputError::new(format!(
+            "Missing input index for {}",
+            dev_name
+        ))),
+        index => Ok(index.parse()?),
+    }
 }
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 fn bind_soundfile(filename: &'static String, base_dir: &Path) -> Result<(), InputError> {
     assert!(!filename.is_empty(), "binding empty filename");
@@ -0,0 +1,588 @@
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::time::{Duration, Instant};
+
+use crate::aliases::Aliases;
+use crate::animation::{Animation, Pattern};
+use crate::bindfiles::{parse_duration, parse_sound_filename};
+use crate::input::bitevents::BitEvent;
+use crate::input::InputError;
+
+// Digits on a clock display unless given
+const CLOCK_DIGITS: usize = 4;
+
+/// A mission as declared in YAML. Inputs and outputs are given by alias or as <device>:<bit>,
+/// durations like 500ms or 10s:
+///
+///   initial: prelaunch
+///   phases:
+///     prelaunch:
+///       transitions:
+///         - on: launch_switch
+///           to: countdown
+///     countdown:
+///       entry:
+///         - countdown: { display: clock, duration: 10s }
+///       transitions:
+///         - after: 10s
+///           to: launch
+///         - on: abort_handle
+///           to: prelaunch
+///           actions:
+///             - sound: sounds/abort.mp3
+///       exit:
+///         - stop_clock: clock
+#[derive(Deserialize, Debug)]
+pub struct MissionConfig {
+    initial: String,
+    phases: BTreeMap<String, PhaseConfig>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct PhaseConfig {
+    #[serde(default)]
+    entry: Vec<ActionConfig>,
+    #[serde(default)]
+    exit: Vec<ActionConfig>,
+    #[serde(default)]
+    transitions: Vec<TransitionConfig>,
+}
+
+/// Taken when an input changes to the value (on by default), or once the phase has lasted for
+/// the time given. Without a phase to go to, only the actions are run
+#[derive(Deserialize, Debug)]
+struct TransitionConfig {
+    on: Option<String>,
+    #[serde(default = "default_value")]
+    value: u8,
+    after: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    actions: Vec<ActionConfig>,
+}
+
+fn default_value() -> u8 {
+    1
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ActionConfig {
+    // A sound file, with an optional volume as in the handler file
+    Sound(String),
+    Set {
+        output: String,
+        value: u8,
+    },
+    Blink {
+        output: String,
+        interval: String,
+        count: Option<usize>,
+    },
+    // Stop whatever is animating an output
+    Stop(String),
+    Countdown {
+        display: String,
+        #[serde(default = "default_digits")]
+        digits: usize,
+        duration: String,
+    },
+    Elapsed {
+        display: String,
+        #[serde(default = "default_digits")]
+        digits: usize,
+    },
+    StopClock(String),
+    // A fixed number, stopping any clock on the display
+    Show {
+        display: String,
+        #[serde(default = "default_digits")]
+        digits: usize,
+        value: i64,
+    },
+    Log(String),
+}
+
+fn default_digits() -> usize {
+    CLOCK_DIGITS
+}
+
+/// Something for the simulator to do as the mission moves along
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Sound(&'static String, f64),
+    Set(BitEvent),
+    Animate(Animation),
+    StopAnimation(String, u8),
+    Countdown {
+        display: String,
+        digits: usize,
+        duration: Duration,
+    },
+    Elapsed {
+        display: String,
+        digits: usize,
+    },
+    StopClock(String),
+    Show {
+        display: String,
+        digits: usize,
+        value: i64,
+    },
+    Log(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Guard {
+    Input((String, u8), u8),
+    After(Duration),
+}
+
+struct Transition {
+    guard: Guard,
+    to: Option<String>,
+    actions: Vec<Action>,
+}
+
+struct Phase {
+    entry: Vec<Action>,
+    exit: Vec<Action>,
+    transitions: Vec<Transition>,
+}
+
+struct Current {
+    phase: String,
+    entered: Instant,
+    // Timed transitions without a phase to go to only run once per visit
+    timers_run: Vec<usize>,
+}
+
+/// The phases of a mission, which one it's in, and what moves it along. The same switch can do
+/// different things in different phases
+pub struct Mission {
+    initial: String,
+    phases: BTreeMap<String, Phase>,
+    // Not started until the first tick
+    current: Option<Current>,
+}
+
+impl Mission {
+    pub fn load(filename: &str, aliases: &Aliases) -> Result<Mission, InputError> {
+        let config: MissionConfig = serde_yaml::from_reader(File::open(filename)?)
+            .map_err(|e| InputError::new(format!("Invalid mission {}: {}", filename, e)))?;
+        Mission::new(config, aliases)
+    }
+
+    /// Check the config over, resolving names and parsing durations up front
+    pub fn new(config: MissionConfig, aliases: &Aliases) -> Result<Mission, InputError> {
+        let check_phase = |phase: &str| {
+            if config.phases.contains_key(phase) {
+                Ok(())
+            } else {
+                Err(InputError::new(format!("Unknown mission phase {}", phase)))
+            }
+        };
+
+        check_phase(&config.initial)?;
+
+        let mut phases = BTreeMap::new();
+        for (name, phase) in &config.phases {
+            let mut transitions = Vec::new();
+
+            for transition in &phase.transitions {
+                let guard = match (&transition.on, &transition.after) {
+                    (Some(input), None) => Guard::Input(aliases.resolve(input)?, transition.value),
+                    (None, Some(after)) => Guard::After(parse_duration(after)?),
+                    _ => {
+                        return Err(InputError::new(format!(
+                            "A transition in {} needs one of on or after",
+                            name
+                        )))
+                    }
+                };
+
+                if let Some(ref to) = transition.to {
+                    check_phase(to)?;
+                }
+
+                transitions.push(Transition {
+                    guard,
+                    to: transition.to.clone(),
+                    actions: resolve_actions(&transition.actions, aliases)?,
+                });
+            }
+
+            phases.insert(
+                name.clone(),
+                Phase {
+                    entry: resolve_actions(&phase.entry, aliases)?,
+                    exit: resolve_actions(&phase.exit, aliases)?,
+                    transitions,
+                },
+            );
+        }
+
+        Ok(Mission {
+            initial: config.initial,
+            phases,
+            current: None,
+        })
+    }
+
+    /// The phase the mission is in, once started
+    pub fn phase(&self) -> Option<&str> {
+        self.current.as_ref().map(|current| current.phase.as_str())
+    }
+
+    /// Every sound the mission plays, which need binding before it starts
+    pub fn sounds(&self) -> Vec<&'static String> {
+        self.phases
+            .values()
+            .flat_map(|phase| {
+                let transitions = phase
+                    .transitions
+                    .iter()
+                    .flat_map(|transition| transition.actions.iter());
+                phase
+                    .entry
+                    .iter()
+                    .chain(phase.exit.iter())
+                    .chain(transitions)
+            })
+            .filter_map(|action| match action {
+                Action::Sound(file, _) => Some(*file),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Take the first transition each input event matches in the current phase
+    pub fn process(&mut self, events: &[BitEvent], now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        for event in events {
+            let matched = self.current_phase().and_then(|phase| {
+                phase.transitions.iter().position(|transition| {
+                    transition.guard
+                        == Guard::Input((event.dev_name.clone(), event.bit), event.value)
+                })
+            });
+
+            if let Some(index) = matched {
+                actions.append(&mut self.take(index, now));
+            }
+        }
+
+        actions
+    }
+
+    /// Start the mission if it hasn't been, and take a timed transition if one is due
+    pub fn tick(&mut self, now: Instant) -> Vec<Action> {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => {
+                let initial = self.initial.clone();
+                return self.enter(initial, now);
+            }
+        };
+
+        let due = self
+            .timers()
+            .find(|&(index, deadline)| deadline <= now && !current.timers_run.contains(&index));
+
+        match due {
+            Some((index, _)) => self.take(index, now),
+            None => vec![],
+        }
+    }
+
+    /// When tick next has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let current = match self.current {
+            Some(ref current) => current,
+            None => return Some(Instant::now()),
+        };
+
+        self.timers()
+            .filter(|(index, _)| !current.timers_run.contains(index))
+            .map(|(_, deadline)| deadline)
+            .min()
+    }
+
+    fn current_phase(&self) -> Option<&Phase> {
+        self.current
+            .as_ref()
+            .and_then(|current| self.phases.get(&current.phase))
+    }
+
+    // The timed transitions of the current phase, and when each is due
+    fn timers(&self) -> impl Iterator<Item = (usize, Instant)> + '_ {
+        let entered = self.current.as_ref().map(|current| current.entered);
+
+        self.current_phase()
+            .into_iter()
+            .flat_map(|phase| phase.transitions.iter().enumerate())
+            .filter_map(move |(index, transition)| match transition.guard {
+                Guard::After(after) => entered.map(|entered| (index, entered + after)),
+                Guard::Input(..) => None,
+            })
+    }
+
+    fn take(&mut self, index: usize, now: Instant) -> Vec<Action> {
+        let (to, mut actions) = match self.current_phase() {
+            Some(phase) => {
+                let transition = &phase.transitions[index];
+                (transition.to.clone(), transition.actions.clone())
+            }
+            None => return vec![],
+        };
+
+        match to {
+            Some(to) => {
+                let mut all = self
+                    .current_phase()
+                    .map_or(vec![], |phase| phase.exit.clone());
+                all.append(&mut actions);
+                all.append(&mut self.enter(to, now));
+                all
+            }
+            None => {
+                if let Some(ref mut current) = self.current {
+                    current.timers_run.push(index);
+                }
+                actions
+            }
+        }
+    }
+
+    fn enter(&mut self, phase: String, now: Instant) -> Vec<Action> {
+        match self.phase() {
+            Some(from) => info!("Mission phase {} -> {}", from, phase),
+            None => info!("Mission starting in {}", phase),
+        }
+
+        let entry = self
+            .phases
+            .get(&phase)
+            .map_or(vec![], |phase| phase.entry.clone());
+        self.current = Some(Current {
+            phase,
+            entered: now,
+            timers_run: Vec::new(),
+        });
+        entry
+    }
+}
+
+fn resolve_actions(actions: &[ActionConfig], aliases: &Aliases) -> Result<Vec<Action>, InputError> {
+    actions
+        .iter()
+        .map(|action| {
+            Ok(match action {
+                ActionConfig::Sound(file) => match parse_sound_filename(file)? {
+                    Some((file, volume)) => Action::Sound(file, volume),
+                    None => return Err(InputError::from_str("Missing sound file")),
+                },
+                ActionConfig::Set { output, value } => {
+                    let (dev_name, bit) = aliases.resolve(output)?;
+                    Action::Set(BitEvent {
+                        dev_name,
+                        bit,
+                        value: *value,
+                    })
+                }
+                ActionConfig::Blink {
+                    output,
+                    interval,
+                    count,
+                } => {
+                    let (dev_name, bit) = aliases.resolve(output)?;
+                    Action::Animate(Animation {
+                        dev_name,
+                        pins: vec![bit],
+                        pattern: Pattern::Blink {
+                            interval: parse_duration(interval)?,
+                            count: *count,
+                        },
+                    })
+                }
+                ActionConfig::Stop(output) => {
+                    let (dev_name, bit) = aliases.resolve(output)?;
+                    Action::StopAnimation(dev_name, bit)
+                }
+                ActionConfig::Countdown {
+                    display,
+                    digits,
+                    duration,
+                } => Action::Countdown {
+                    display: display.clone(),
+                    digits: *digits,
+                    duration: parse_duration(duration)?,
+                },
+                ActionConfig::Elapsed { display, digits } => Action::Elapsed {
+                    display: display.clone(),
+                    digits: *digits,
+                },
+                ActionConfig::StopClock(display) => Action::StopClock(display.clone()),
+                ActionConfig::Show {
+                    display,
+                    digits,
+                    value,
+                } => Action::Show {
+                    display: display.clone(),
+                    digits: *digits,
+                    value: *value,
+                },
+                ActionConfig::Log(message) => Action::Log(message.clone()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Mission};
+    use crate::aliases::Aliases;
+    use crate::input::bitevents::BitEvent;
+    use std::time::{Duration, Instant};
+
+    const MISSION: &str = "
+initial: prelaunch
+phases:
+  prelaunch:
+    entry:
+      - log: Ready
+    transitions:
+      - on: launch_switch
+        to: countdown
+      - on: abort_handle
+        actions:
+          - log: Nothing to abort
+  countdown:
+    entry:
+      - countdown: { display: clock, duration: 10s }
+    exit:
+      - stop_clock: clock
+    transitions:
+      - after: 10s
+        to: launch
+      - after: 5s
+        actions:
+          - set: { output: main_a:1, value: 1 }
+      - on: abort_handle
+        to: prelaunch
+        actions:
+          - show: { display: clock, value: 0 }
+  launch:
+    entry:
+      - elapsed: { display: clock }
+";
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
+            dev_name: String::from(dev_name),
+            bit,
+            value,
+        }
+    }
+
+    fn mission() -> Mission {
+        let aliases = Aliases::parse("launch_switch = main_a:0\nabort_handle = main_c:7").unwrap();
+        Mission::new(serde_yaml::from_str(MISSION).unwrap(), &aliases).unwrap()
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut mission = mission();
+        let start = Instant::now();
+        let seconds = |seconds| start + Duration::from_secs(seconds);
+
+        assert_eq!(mission.phase(), None);
+        assert_eq!(
+            mission.tick(start),
+            vec![Action::Log(String::from("Ready"))]
+        );
+        assert_eq!(mission.phase(), Some("prelaunch"));
+
+        // The abort handle only does something once there's something to abort
+        assert_eq!(
+            mission.process(&[event("main_c", 7, 1), event("main_c", 7, 0)], start),
+            vec![Action::Log(String::from("Nothing to abort"))]
+        );
+        assert_eq!(mission.phase(), Some("prelaunch"));
+        assert_eq!(mission.next_deadline(), None);
+
+        assert_eq!(
+            mission.process(&[event("main_a", 0, 1)], start),
+            vec![Action::Countdown {
+                display: String::from("clock"),
+                digits: 4,
+                duration: Duration::from_secs(10),
+            }]
+        );
+        assert_eq!(mission.next_deadline(), Some(seconds(5)));
+
+        // The halfway action only runs once
+        assert_eq!(
+            mission.tick(seconds(6)),
+            vec![Action::Set(event("main_a", 1, 1))]
+        );
+        assert_eq!(mission.tick(seconds(7)), vec![]);
+        assert_eq!(mission.next_deadline(), Some(seconds(10)));
+
+        assert_eq!(
+            mission.tick(seconds(10)),
+            vec![
+                Action::StopClock(String::from("clock")),
+                Action::Elapsed {
+                    display: String::from("clock"),
+                    digits: 4,
+                }
+            ]
+        );
+        assert_eq!(mission.phase(), Some("launch"));
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut mission = mission();
+        let start = Instant::now();
+
+        mission.tick(start);
+        mission.process(&[event("main_a", 0, 1)], start);
+        assert_eq!(
+            mission.process(&[event("main_c", 7, 1)], start),
+            vec![
+                Action::StopClock(String::from("clock")),
+                Action::Show {
+                    display: String::from("clock"),
+                    digits: 4,
+                    value: 0,
+                },
+                Action::Log(String::from("Ready"))
+            ]
+        );
+        assert_eq!(mission.phase(), Some("prelaunch"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let aliases = Aliases::default();
+        let invalid = |yaml: &str| Mission::new(serde_yaml::from_str(yaml).unwrap(), &aliases);
+
+        assert!(invalid("{initial: orbit, phases: {launch: {}}}").is_err());
+        assert!(invalid(
+            "{initial: launch, phases: {launch: {transitions: [{to: orbit, after: 1s}]}}}"
+        )
+        .is_err());
+        assert!(
+            invalid("{initial: launch, phases: {launch: {transitions: [{to: launch}]}}}").is_err()
+        );
+        assert!(invalid(
+            "{initial: launch, phases: {launch: {transitions: [{on: main_a, to: launch}]}}}"
+        )
+        .is_err());
+    }
+}
@@ -1,6 +1,16 @@
+use crate::aliases::Aliases;
+use crate::animation::Scheduler;
+use crate::display::{self, Clock, Clocks};
+use crate::gesture::{Gesture, Gestures};
 use crate::input::bitevents::BitEvent;
+use crate::input::InputError;
+use crate::interlock::{Condition, InputState};
+use crate::mission::{Action, Mission};
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::slice;
 use std::sync::mpsc::Sender;
+use std::time::Instant;
 
 pub fn default_handler_event() -> (String, u8) {
     (String::from(crate::DEFAULT_NAME), 0)
@@ -12,81 +22,417 @@ pub type HandlerMap = BTreeMap<(String, u8), EventHandler>;
 pub struct Simulator {
     handlers: HandlerMap,
     sender: Sender<BitEvent>,
+    animations: RefCell<Scheduler>,
+    clocks: RefCell<Clocks>,
+    gestures: RefCell<Gestures>,
+    aliases: Aliases,
+    mission: Option<RefCell<Mission>>,
+    // Where every input is, as far as the events so far say, for interlocks
+    inputs: RefCell<InputState>,
 }
 
 impl Simulator {
-    pub fn new(handlers: HandlerMap, sender: &Sender<BitEvent>) -> Simulator {
-        Simulator {
+    /// Handlers bound to gestures rather than plain inputs have them recognized as well
+    pub fn new(
+        handlers: HandlerMap,
+        aliases: Aliases,
+        mission: Option<Mission>,
+        sender: &Sender<BitEvent>,
+    ) -> Result<Simulator, InputError> {
+        let gestures = handlers
+            .keys()
+            .filter_map(|(dev_name, bit)| Gesture::parse(dev_name, *bit, &aliases))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Simulator {
             handlers,
             sender: (*sender).clone(),
-        }
+            animations: RefCell::new(Scheduler::new()),
+            clocks: RefCell::new(Clocks::new()),
+            gestures: RefCell::new(Gestures::new(gestures)),
+            aliases,
+            mission: mission.map(RefCell::new),
+            inputs: RefCell::new(InputState::new()),
+        })
+    }
+
+    /// The names inputs and outputs go by
+    pub fn aliases(&self) -> &Aliases {
+        &self.aliases
     }
 
     pub fn process(&self, events: &[BitEvent]) {
         debug!("Processing {} simulation input events", events.len());
+        self.fire_inputs(events, false);
+
+        let now = Instant::now();
+        let gestures = self.gestures.borrow_mut().process(events, now);
+        self.fire(&gestures, false);
+
+        if let Some(ref mission) = self.mission {
+            let actions = mission.borrow_mut().process(events, now);
+            self.perform(&actions, now);
+        }
+    }
+
+    /// Fire any gestures that only complete with the passage of time, like long presses, and
+    /// move the mission along on its timers
+    pub fn expire(&self, now: Instant) {
+        let gestures = self.gestures.borrow_mut().tick(now);
+        self.fire(&gestures, false);
+
+        if let Some(ref mission) = self.mission {
+            let actions = mission.borrow_mut().tick(now);
+            self.perform(&actions, now);
+        }
+    }
+
+    /// Tell the handlers where every input is at startup. These aren't changes anybody made, so
+    /// handlers are told to treat them as such
+    pub fn sync(&self, events: &[BitEvent]) {
+        debug!("Syncing {} initial input states", events.len());
+        self.fire_inputs(events, true);
+    }
+
+    /// Step any running output animations and clocks, returning the outputs to set
+    pub fn animate(&self, now: Instant) -> Vec<BitEvent> {
+        let mut events = self.animations.borrow_mut().tick(now);
+        events.append(&mut self.clocks.borrow_mut().tick(now));
+        events
+    }
+
+    /// When animate or expire next has something to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let animations = self.animations.borrow().next_deadline();
+        let clocks = self.clocks.borrow().next_deadline();
+        let gestures = self.gestures.borrow().next_deadline();
+        let mission = self
+            .mission
+            .as_ref()
+            .and_then(|mission| mission.borrow().next_deadline());
+        animations
+            .into_iter()
+            .chain(clocks)
+            .chain(gestures)
+            .chain(mission)
+            .min()
+    }
+
+    fn perform(&self, actions: &[Action], now: Instant) {
+        for action in actions {
+            debug!("Mission action {:?}", action);
+
+            match action {
+                Action::Sound(file, volume) => {
+                    music::play_sound(file, music::Repeat::Times(0), *volume);
+                }
+                Action::Set(event) => {
+                    self.sender.send(event.clone()).unwrap_or_else(|err| {
+                        warn!("Error sending {}: {}", event, err);
+                    });
+                }
+                Action::Animate(animation) => {
+                    self.animations.borrow_mut().start(animation.clone(), now);
+                }
+                Action::StopAnimation(dev_name, pin) => {
+                    self.animations.borrow_mut().stop(dev_name, *pin);
+                }
+                Action::Countdown {
+                    display,
+                    digits,
+                    duration,
+                } => {
+                    let clock = Clock::Countdown {
+                        until: now + *duration,
+                    };
+                    self.clocks.borrow_mut().start(display, *digits, clock, now);
+                }
+                Action::Elapsed { display, digits } => {
+                    let clock = Clock::Elapsed { since: now };
+                    self.clocks.borrow_mut().start(display, *digits, clock, now);
+                }
+                Action::StopClock(display) => self.clocks.borrow_mut().stop(display),
+                Action::Show {
+                    display,
+                    digits,
+                    value,
+                } => {
+                    self.clocks.borrow_mut().stop(display);
+                    for event in display::number(display, *digits, *value) {
+                        self.sender.send(event).unwrap_or_else(|err| {
+                            warn!("Error sending to {}: {}", display, err);
+                        });
+                    }
+                }
+                Action::Log(message) => info!("{}", message),
+            }
+        }
+    }
+
+    /// Fire the handlers for events from the inputs themselves, keeping track of where each input
+    /// is as it goes, so that interlocks see anything earlier in the same batch
+    fn fire_inputs(&self, events: &[BitEvent], initial: bool) {
         for event in events {
+            self.inputs
+                .borrow_mut()
+                .insert((event.dev_name.clone(), event.bit), event.value);
+            self.fire(slice::from_ref(event), initial);
+        }
+    }
+
+    fn fire(&self, events: &[BitEvent], initial: bool) {
+        let context = EventContext {
+            initial,
+            animations: &self.animations,
+        };
+
+        for event in events {
+            let key = (event.dev_name.clone(), event.bit);
+
             let target_handler = self
                 .handlers
-                .get(&(event.dev_name.clone(), event.bit))
+                .get(&key)
                 .or_else(|| self.handlers.get(&default_handler_event()));
 
+            // The initial state is only reported, so there's nothing to interlock
+            let target_handler = match target_handler {
+                Some(EventHandler {
+                    name,
+                    condition: Some(ref condition),
+                    alternate,
+                    ..
+                }) if !initial && !condition.holds(&self.inputs.borrow()) => {
+                    info!(
+                        "'{}' is interlocked until {}, ignoring {}",
+                        name,
+                        condition,
+                        self.aliases.describe(event)
+                    );
+                    match alternate {
+                        Some(alternate) => Some(alternate.as_ref()),
+                        None => continue,
+                    }
+                }
+                target_handler => target_handler,
+            };
+
             if let Some(to_fire) = target_handler {
-                info!("Firing '{}' for event {:?}", to_fire.name, event);
-                (to_fire.handler)(event.value, &self.sender);
+                info!(
+                    "Firing '{}' for event {}",
+                    to_fire.name,
+                    self.aliases.describe(event)
+                );
+                (to_fire.handler)(event.value, &context, &self.sender);
             } else {
-                warn!("Event without a handler: {}", event);
+                warn!("Event without a handler: {}", self.aliases.describe(event));
             }
         }
     }
 }
 
-pub type HandlerFunc = Box<dyn Fn(u8, &Sender<BitEvent>) -> ()>;
+/// What a handler knows about the event it is handling, beyond the value
+pub struct EventContext<'a> {
+    // The event reports where an input was at startup, rather than a change to it
+    pub initial: bool,
+    // Output animations, run from the main loop so that handlers never have to wait on them
+    pub animations: &'a RefCell<Scheduler>,
+}
+
+pub type HandlerFunc = Box<dyn Fn(u8, &EventContext, &Sender<BitEvent>)>;
 
 pub struct EventHandler {
     name: &'static str,
     handler: HandlerFunc,
+    // Only fire when other inputs allow it, otherwise fire the alternate, if any
+    condition: Option<Condition>,
+    alternate: Option<Box<EventHandler>>,
 }
 
 impl EventHandler {
     pub fn new(name: &'static str, handler: HandlerFunc) -> EventHandler {
-        EventHandler { name, handler }
+        EventHandler {
+            name,
+            handler,
+            condition: None,
+            alternate: None,
+        }
+    }
+
+    /// Make the handler depend on other inputs, like an arming switch
+    pub fn interlocked(
+        self,
+        condition: Condition,
+        alternate: Option<EventHandler>,
+    ) -> EventHandler {
+        EventHandler {
+            condition: Some(condition),
+            alternate: alternate.map(Box::new),
+            ..self
+        }
     }
 }
 
-// Utility functions
-use std::thread;
-use std::time::Duration;
-
-/// Blink the given output on/off (one interval each) for the specified count
-pub fn blink(
-    dev_name: &str,
-    output_id: u8,
-    count: usize,
-    interval: Duration,
-    tx: &Sender<BitEvent>,
-) {
-    debug!("Blinking {} times with interval {:?}", count, interval);
-    for _ in 0..count {
-        tx.send(BitEvent {
-            dev_name: String::from(dev_name),
-            bit: output_id,
-            value: 1,
-        })
-        .unwrap();
-        thread::sleep(interval);
-        tx.send(BitEvent {
+#[cfg(test)]
+mod tests {
+    use super::{EventHandler, HandlerMap, Simulator};
+    use crate::aliases::Aliases;
+    use crate::animation::Animation;
+    use crate::bindfiles::create_handler;
+    use crate::input::bitevents::BitEvent;
+    use crate::input::selector::{decode_selectors, Encoding, Selector, SelectorConfig};
+    use crate::interlock::Condition;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    fn event(dev_name: &str, bit: u8, value: u8) -> BitEvent {
+        BitEvent {
             dev_name: String::from(dev_name),
-            bit: output_id,
-            value: 0,
-        })
-        .unwrap();
-        thread::sleep(interval);
-    }
-    // Finally, turn it on once done blinking
-    tx.send(BitEvent {
-        dev_name: String::from(dev_name),
-        bit: output_id,
-        value: 1,
-    })
-    .unwrap();
+            bit,
+            value,
+        }
+    }
+
+    // A handler that lights an output, so it's easy to tell which one fired
+    fn light(name: &'static str, bit: u8) -> EventHandler {
+        EventHandler::new(
+            name,
+            Box::new(move |value, _, sender| {
+                sender.send(event("lights", bit, value)).unwrap();
+            }),
+        )
+    }
+
+    #[test]
+    fn test_interlock() {
+        let aliases = Aliases::parse("retro_arm = main_b:3\nfire_retro = main_b:4").unwrap();
+        let condition = Condition::parse("retro_arm=1", &aliases).unwrap();
+
+        let mut handlers = HandlerMap::new();
+        handlers.insert(
+            (String::from("main_b"), 4),
+            light("fire_retro", 0).interlocked(condition, Some(light("buzz", 1))),
+        );
+
+        let (tx, rx) = channel();
+        let sim = Simulator::new(handlers, aliases, None, &tx).unwrap();
+
+        sim.process(&[event("main_b", 4, 1)]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![event("lights", 1, 1)]
+        );
+
+        // Arming it in the same batch counts
+        sim.process(&[
+            event("main_b", 4, 0),
+            event("main_b", 3, 1),
+            event("main_b", 4, 1),
+        ]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![event("lights", 1, 0), event("lights", 0, 1)]
+        );
+
+        // The initial state isn't held back
+        sim.process(&[event("main_b", 3, 0)]);
+        sim.sync(&[event("main_b", 4, 0)]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![event("lights", 0, 0)]
+        );
+    }
+
+    #[test]
+    fn test_gestures_are_not_inputs() {
+        let mut handlers = HandlerMap::new();
+        handlers.insert(
+            (String::from("chord:main_b:1+main_b:2"), 0),
+            light("both", 0),
+        );
+
+        let (tx, rx) = channel();
+        let sim = Simulator::new(handlers, Aliases::default(), None, &tx).unwrap();
+
+        sim.process(&[event("main_b", 1, 1), event("main_b", 2, 1)]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![event("lights", 0, 1)]
+        );
+        assert_eq!(
+            sim.inputs.borrow().keys().cloned().collect::<Vec<_>>(),
+            vec![(String::from("main_b"), 1), (String::from("main_b"), 2)]
+        );
+    }
+
+    #[test]
+    fn test_selector_leaves_position() {
+        let condition = Condition::parse("mode:1=1", &Aliases::default()).unwrap();
+
+        let mut handlers = HandlerMap::new();
+        handlers.insert(
+            (String::from("main_b"), 4),
+            light("fire_retro", 0).interlocked(condition, Some(light("buzz", 1))),
+        );
+
+        let (tx, rx) = channel();
+        let sim = Simulator::new(handlers, Aliases::default(), None, &tx).unwrap();
+
+        let mut selectors = vec![Selector::new(SelectorConfig {
+            name: String::from("mode"),
+            pins: vec![0, 1, 2],
+            encoding: Encoding::OneHot,
+            settle_ms: 0,
+        })];
+        selectors[0].reset(0b001);
+        let mut turn = |from, to| {
+            let pins = vec![event("main_a", from, 0), event("main_a", to, 1)];
+            decode_selectors(&mut selectors, pins, Instant::now())
+        };
+
+        sim.process(&turn(0, 1));
+        sim.process(&[event("main_b", 4, 1)]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![event("lights", 0, 1)]
+        );
+
+        // Moving on to position 2 takes position 1 with it
+        sim.process(&turn(1, 2));
+        sim.process(&[event("main_b", 4, 1)]);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![event("lights", 1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_handler_animation() {
+        let aliases = Aliases::parse("beacon = main_b:2").unwrap();
+        let animation = Animation::parse("chase 100ms leds:0 leds:1", &aliases).unwrap();
+
+        let mut handlers = HandlerMap::new();
+        handlers.insert(
+            (String::from("main_b"), 2),
+            create_handler("beacon", None, None, Some(animation)).unwrap(),
+        );
+
+        let (tx, _rx) = channel();
+        let sim = Simulator::new(handlers, aliases, None, &tx).unwrap();
+
+        sim.process(&[event("main_b", 2, 1)]);
+        let start = Instant::now();
+        assert_eq!(
+            sim.animate(start),
+            vec![event("leds", 0, 1), event("leds", 1, 0)]
+        );
+        assert!(sim.next_deadline() <= Some(start + Duration::from_millis(100)));
+
+        // Switching off stops it, leaving the outputs off
+        sim.process(&[event("main_b", 2, 0)]);
+        assert_eq!(
+            sim.animate(Instant::now()),
+            vec![event("leds", 0, 0), event("leds", 1, 0)]
+        );
+        assert_eq!(sim.next_deadline(), None);
+    }
 }